@@ -3,11 +3,7 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 use paste::paste;
 use shrinkwraprs::Shrinkwrap;
 
-use crate::{
-    config,
-    heap::{StringId, ValueId},
-    types::Line,
-};
+use crate::{heap::StringId, types::Line, value::Value};
 
 #[derive(Shrinkwrap, Clone, Copy)]
 #[shrinkwrap(mutable)]
@@ -40,6 +36,7 @@ pub enum OpCode {
     Constant,
     ConstantLong,
     Closure,
+    ClosureLong,
 
     DefineGlobal,
     DefineGlobalLong,
@@ -84,19 +81,80 @@ pub enum OpCode {
     Not,
 
     Print,
+    /// `Print`, but skipping `nil`; the REPL's expression statements
+    Echo,
     Return,
 
     Class,
+    ClassLong,
+    /// `Class`, for `trait` declarations
+    Trait,
+    TraitLong,
+    /// Copies the methods of the traits below the class on the stack into it; the operand is how
+    /// many traits there are
+    MixIn,
     GetProperty,
+    GetPropertyLong,
     SetProperty,
+    SetPropertyLong,
     Method,
+    MethodLong,
+    /// `Method`, for `class` methods called on the class itself
+    StaticMethod,
+    StaticMethodLong,
+    /// `Method`, for `get` accessors
+    Getter,
+    GetterLong,
+    /// `Method`, for `set` accessors
+    Setter,
+    SetterLong,
+    /// Declares a `const` instance field on the class on top of the stack
+    ConstField,
+    ConstFieldLong,
     Invoke,
+    InvokeLong,
     Inherit,
     GetSuper,
+    GetSuperLong,
     SuperInvoke,
+    SuperInvokeLong,
+    /// Replaces the value a `for-in` loop iterates over with its iterator
+    GetIter,
+    /// Pushes whether the iterator in the local given as the operand has more values; the local
+    /// after it holds the position in built-in iterables
+    IterHasNext,
+    IterHasNextLong,
+    /// Pushes the next value of the iterator in the local given as the operand
+    IterNext,
+    IterNextLong,
+    /// Suspends the generator running in the current frame, handing the popped value to whatever
+    /// resumed it
+    Yield,
+
+    // Superinstructions, only emitted by the optimizer
+    /// `GetLocal a, GetLocal b, Add`
+    AddLocals,
+    /// `GetLocal a, Constant b, Less`
+    LessLocalConstant,
+    /// `JumpIfFalse, Pop`; pops the condition only if the jump is not taken
+    JumpIfFalsePop,
+    /// `Constant, Return`
+    ReturnConstant,
 }
 
 impl OpCode {
+    /// As in `clox`'s disassembler: `OP_GET_LOCAL` for `GetLocal`
+    pub fn name(self) -> String {
+        let mut name = "OP".to_string();
+        for c in format!("{:?}", self).chars() {
+            if c.is_ascii_uppercase() {
+                name.push('_');
+            }
+            name.push(c.to_ascii_uppercase());
+        }
+        name
+    }
+
     pub fn to_long(self) -> OpCode {
         match self {
             OpCode::GetLocal => OpCode::GetLocalLong,
@@ -105,9 +163,55 @@ impl OpCode {
             OpCode::SetGlobal => OpCode::SetGlobalLong,
             OpCode::DefineGlobal => OpCode::DefineGlobalLong,
             OpCode::DefineGlobalConst => OpCode::DefineGlobalConstLong,
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::Trait => OpCode::TraitLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::StaticMethod => OpCode::StaticMethodLong,
+            OpCode::Getter => OpCode::GetterLong,
+            OpCode::Setter => OpCode::SetterLong,
+            OpCode::ConstField => OpCode::ConstFieldLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::IterHasNext => OpCode::IterHasNextLong,
+            OpCode::IterNext => OpCode::IterNextLong,
             x => x,
         }
     }
+
+    /// Whether the first operand is 24 bits wide
+    pub fn is_long(self) -> bool {
+        use OpCode::*;
+        matches!(
+            self,
+            ConstantLong
+                | ClosureLong
+                | DefineGlobalLong
+                | DefineGlobalConstLong
+                | GetGlobalLong
+                | SetGlobalLong
+                | GetLocalLong
+                | SetLocalLong
+                | IterHasNextLong
+                | IterNextLong
+                | ClassLong
+                | TraitLong
+                | GetPropertyLong
+                | SetPropertyLong
+                | MethodLong
+                | StaticMethodLong
+                | GetterLong
+                | SetterLong
+                | ConstFieldLong
+                | InvokeLong
+                | GetSuperLong
+                | SuperInvokeLong
+        )
+    }
 }
 
 #[derive(PartialEq, Derivative, Clone)]
@@ -117,7 +221,7 @@ pub struct Chunk {
     pub code: Vec<u8>,
     #[derivative(PartialOrd = "ignore")]
     lines: Vec<(usize, Line)>,
-    constants: Vec<ValueId>,
+    constants: Vec<Value>,
 }
 
 impl Chunk {
@@ -130,7 +234,14 @@ impl Chunk {
         }
     }
 
-    pub fn constants(&self) -> &[ValueId] {
+    /// Bytes owned by the chunk outside of the `Chunk` itself
+    pub fn owned_bytes(&self) -> usize {
+        self.code.capacity()
+            + self.lines.capacity() * std::mem::size_of::<(usize, Line)>()
+            + self.constants.capacity() * std::mem::size_of::<Value>()
+    }
+
+    pub fn constants(&self) -> &[Value] {
         &self.constants
     }
 
@@ -138,7 +249,7 @@ impl Chunk {
         &self.code
     }
 
-    pub fn get_constant<T>(&self, index: T) -> &ValueId
+    pub fn get_constant<T>(&self, index: T) -> &Value
     where
         T: Into<usize>,
     {
@@ -165,18 +276,19 @@ impl Chunk {
         self.code[*offset] = what.into();
     }
 
-    pub fn make_constant(&mut self, what: ValueId) -> ConstantLongIndex {
+    pub fn make_constant(&mut self, what: Value) -> ConstantLongIndex {
         self.constants.push(what);
         ConstantLongIndex(self.constants.len() - 1)
     }
 
-    pub fn write_constant(&mut self, what: ValueId, line: Line) -> bool {
+    /// In standards mode, only 256 constants can be written, as in `clox`
+    pub fn write_constant(&mut self, what: Value, line: Line, std_mode: bool) -> bool {
         let long_index = self.make_constant(what);
         if let Ok(short_index) = u8::try_from(*long_index) {
             self.write(OpCode::Constant, line);
             self.write(short_index, line);
             true
-        } else if !config::STD_MODE.load() {
+        } else if !std_mode {
             self.write(OpCode::ConstantLong, line);
             self.write_24bit_number(*long_index, line)
         } else {
@@ -184,6 +296,114 @@ impl Chunk {
         }
     }
 
+    /// Replaces the code and line information, keeping the constants
+    pub fn replace_code<I>(&mut self, code: I)
+    where
+        I: IntoIterator<Item = (u8, Line)>,
+    {
+        self.code.clear();
+        self.lines.clear();
+        for (byte, line) in code {
+            self.write(byte, line);
+        }
+    }
+
+    /// The most stack slots a call of this chunk's function can occupy, counting from the base of
+    /// its frame. `initial_depth` is the number of slots taken on entry: the callee and its
+    /// arguments.
+    pub fn max_stack_depth(&self, initial_depth: usize) -> usize {
+        let disassembler = InstructionDisassembler::new(self);
+        let mut visited = vec![false; self.code.len()];
+        let mut worklist = vec![(0, initial_depth)];
+        let mut max_depth = initial_depth;
+
+        // Every path reaching an instruction has the same stack depth there, so it's enough to
+        // visit each instruction once
+        while let Some((offset, depth)) = worklist.pop() {
+            if offset >= self.code.len() || visited[offset] {
+                continue;
+            }
+            visited[offset] = true;
+
+            let (pops, pushes) = self.stack_effect(offset);
+            max_depth = max_depth.max(depth + self.peak_pushes(offset));
+            let depth = depth.saturating_sub(pops) + pushes;
+            max_depth = max_depth.max(depth);
+
+            let next = offset + disassembler.instruction_len(offset);
+            match OpCode::try_from_primitive(self.code[offset]).unwrap() {
+                OpCode::Return | OpCode::ReturnConstant => {}
+                OpCode::Jump | OpCode::Loop => {
+                    worklist.push((disassembler.jump_target(offset), depth));
+                }
+                OpCode::JumpIfFalse => {
+                    worklist.push((disassembler.jump_target(offset), depth));
+                    worklist.push((next, depth));
+                }
+                OpCode::JumpIfFalsePop => {
+                    worklist.push((disassembler.jump_target(offset), depth));
+                    worklist.push((next, depth.saturating_sub(1)));
+                }
+                _ => worklist.push((next, depth)),
+            }
+        }
+
+        max_depth
+    }
+
+    /// How many values the instruction at `offset` pops, and how many it then pushes
+    pub fn stack_effect(&self, offset: usize) -> (usize, usize) {
+        use OpCode::*;
+        let opcode = OpCode::try_from_primitive(self.code[offset]).unwrap();
+        match opcode {
+            Constant | ConstantLong | Closure | ClosureLong | GetGlobal | GetGlobalLong
+            | GetUpvalue | GetLocal | GetLocalLong | Nil | True | False | Dup | Class
+            | ClassLong | Trait | TraitLong | AddLocals | LessLocalConstant | ReturnConstant
+            | IterHasNext | IterHasNextLong | IterNext | IterNextLong => (0, 1),
+            SetGlobal | SetGlobalLong | SetUpvalue | SetLocal | SetLocalLong | Jump
+            | JumpIfFalse | Loop | JumpIfFalsePop | Negate | Not | GetProperty
+            | GetPropertyLong | ConstField | ConstFieldLong => (0, 0),
+            GetIter => (1, 1),
+            Yield => (1, 0),
+            DefineGlobal
+            | DefineGlobalLong
+            | DefineGlobalConst
+            | DefineGlobalConstLong
+            | CloseUpvalue
+            | Pop
+            | Print
+            | Echo
+            | Method
+            | MethodLong
+            | StaticMethod
+            | StaticMethodLong
+            | Getter
+            | GetterLong
+            | Setter
+            | SetterLong
+            | Inherit
+            | Return => (1, 0),
+            Equal | Greater | Less | Add | Subtract | Multiply | Divide | SetProperty
+            | SetPropertyLong | GetSuper | GetSuperLong => (2, 1),
+            Call => (usize::from(self.code[offset + 1]) + 1, 1),
+            MixIn => (usize::from(self.code[offset + 1]) + 1, 0),
+            Invoke => (usize::from(self.code[offset + 2]) + 1, 1),
+            InvokeLong => (usize::from(self.code[offset + 4]) + 1, 1),
+            SuperInvoke => (usize::from(self.code[offset + 2]) + 2, 1),
+            SuperInvokeLong => (usize::from(self.code[offset + 4]) + 2, 1),
+        }
+    }
+
+    /// How many values the instruction at `offset` can have pushed at once before it finishes,
+    /// where that's more than `stack_effect` says it leaves: the slow paths of `OP_ADD_LOCALS` and
+    /// `OP_LESS_LOCAL_CONSTANT` push both operands to fall back to `OP_ADD` or `OP_LESS`
+    pub fn peak_pushes(&self, offset: usize) -> usize {
+        match OpCode::try_from_primitive(self.code[offset]).unwrap() {
+            OpCode::AddLocals | OpCode::LessLocalConstant => 2,
+            _ => 0,
+        }
+    }
+
     pub fn write_24bit_number(&mut self, what: usize, line: Line) -> bool {
         let (a, b, c, d) = crate::bitwise::get_4_bytes(what);
         if a > 0 {
@@ -208,6 +428,19 @@ impl std::fmt::Debug for Chunk {
     }
 }
 
+/// An operand of an instruction, decoded
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    /// A stack slot, upvalue index or argument count
+    Byte(usize),
+    /// An index into the chunk's constants
+    Constant(usize),
+    /// Where a jump lands; the encoded operand is the distance from the next instruction
+    Jump(usize),
+    /// One of the upvalues captured by `OP_CLOSURE`
+    Upvalue { is_local: bool, index: usize },
+}
+
 // Debug helpers
 pub struct InstructionDisassembler<'chunk> {
     chunk: &'chunk Chunk,
@@ -223,35 +456,143 @@ impl<'chunk> InstructionDisassembler<'chunk> {
         }
     }
 
-    fn instruction_len(&self, offset: usize) -> usize {
+    pub fn instruction_len(&self, offset: usize) -> usize {
         let opcode = OpCode::try_from_primitive(self.chunk.code[offset]).unwrap();
         use OpCode::*;
         std::mem::size_of::<OpCode>()
             + match opcode {
                 Negate | Add | Subtract | Multiply | Divide | Nil | True | False | Not | Equal
-                | Greater | Less | Print | Pop | Dup | CloseUpvalue | Inherit => 0,
+                | Greater | Less | Print | Echo | Pop | Dup | CloseUpvalue | Inherit | Return
+                | GetIter | Yield => 0,
                 Constant | GetLocal | SetLocal | GetGlobal | SetGlobal | DefineGlobal
-                | DefineGlobalConst | Return | Call | GetUpvalue | SetUpvalue | Class
-                | GetProperty | SetProperty | Method | GetSuper => 1,
-                JumpIfFalse | Jump | Loop | Invoke | SuperInvoke => 2,
+                | DefineGlobalConst | Call | GetUpvalue | SetUpvalue | Class | Trait | MixIn
+                | IterHasNext | IterNext | GetProperty | SetProperty | Method | StaticMethod
+                | Getter | Setter | ConstField | GetSuper | ReturnConstant => 1,
+                JumpIfFalse | Jump | Loop | Invoke | SuperInvoke | AddLocals
+                | LessLocalConstant | JumpIfFalsePop => 2,
                 ConstantLong
                 | GetGlobalLong
                 | SetGlobalLong
                 | DefineGlobalLong
                 | DefineGlobalConstLong
                 | GetLocalLong
-                | SetLocalLong => 3,
+                | SetLocalLong
+                | IterHasNextLong
+                | IterNextLong
+                | ClassLong
+                | TraitLong
+                | GetPropertyLong
+                | SetPropertyLong
+                | MethodLong
+                | StaticMethodLong
+                | GetterLong
+                | SetterLong
+                | ConstFieldLong
+                | GetSuperLong => 3,
+                InvokeLong | SuperInvokeLong => 4,
                 Closure => 1 + self.upvalue_code_len(offset),
+                ClosureLong => 3 + self.upvalue_code_len(offset),
             }
     }
 
-    fn upvalue_code_len(&self, closure_offset: usize) -> usize {
+    /// Where the `Jump`, `JumpIfFalse`, `JumpIfFalsePop` or `Loop` at `offset` lands
+    pub fn jump_target(&self, offset: usize) -> usize {
         let code = self.chunk.code();
-        let constant = code[closure_offset + 1];
-        let value = &**self.chunk.get_constant(constant);
+        let jump = (usize::from(code[offset + 1]) << 8) + (usize::from(code[offset + 2]));
+        let next = offset + self.instruction_len(offset);
+        if OpCode::try_from_primitive(code[offset]).unwrap() == OpCode::Loop {
+            next - jump
+        } else {
+            next + jump
+        }
+    }
+
+    /// The operands of the instruction at `self.offset`
+    pub fn operands(&self) -> Vec<Operand> {
+        use OpCode::*;
+        let code = self.chunk.code();
+        let offset = *self.offset;
+        let byte = |i: usize| usize::from(code[offset + i]);
+        let long = || (byte(1) << 16) + (byte(2) << 8) + byte(3);
+        let opcode = OpCode::try_from_primitive(code[offset]).unwrap();
+        match opcode {
+            Constant | DefineGlobal | DefineGlobalConst | GetGlobal | SetGlobal | Class | Trait
+            | GetProperty | SetProperty | Method | StaticMethod | Getter | Setter | ConstField
+            | GetSuper | ReturnConstant => {
+                vec![Operand::Constant(byte(1))]
+            }
+            ConstantLong
+            | DefineGlobalLong
+            | DefineGlobalConstLong
+            | GetGlobalLong
+            | SetGlobalLong
+            | ClassLong
+            | TraitLong
+            | GetPropertyLong
+            | SetPropertyLong
+            | MethodLong
+            | StaticMethodLong
+            | GetterLong
+            | SetterLong
+            | ConstFieldLong
+            | GetSuperLong => vec![Operand::Constant(long())],
+            Call | MixIn | GetUpvalue | SetUpvalue | GetLocal | SetLocal | IterHasNext
+            | IterNext => {
+                vec![Operand::Byte(byte(1))]
+            }
+            GetLocalLong | SetLocalLong | IterHasNextLong | IterNextLong => {
+                vec![Operand::Byte(long())]
+            }
+            Jump | JumpIfFalse | JumpIfFalsePop | Loop => {
+                vec![Operand::Jump(self.jump_target(offset))]
+            }
+            AddLocals => vec![Operand::Byte(byte(1)), Operand::Byte(byte(2))],
+            LessLocalConstant => vec![Operand::Byte(byte(1)), Operand::Constant(byte(2))],
+            Invoke | SuperInvoke => vec![Operand::Constant(byte(1)), Operand::Byte(byte(2))],
+            InvokeLong | SuperInvokeLong => {
+                vec![Operand::Constant(long()), Operand::Byte(byte(4))]
+            }
+            Closure | ClosureLong => {
+                let constant = self.closure_constant(offset);
+                // The upvalues follow the constant index
+                let upvalues = if opcode == ClosureLong { 4 } else { 2 };
+                let upvalue_count = self
+                    .chunk
+                    .get_constant(constant)
+                    .as_function()
+                    .upvalue_count;
+                std::iter::once(Operand::Constant(constant))
+                    .chain((0..upvalue_count).map(|i| Operand::Upvalue {
+                        is_local: byte(upvalues + 2 * i) == 1,
+                        index: byte(upvalues + 1 + 2 * i),
+                    }))
+                    .collect()
+            }
+            Negate | Add | Subtract | Multiply | Divide | Nil | True | False | Not | Equal
+            | Greater | Less | Print | Echo | Pop | Dup | CloseUpvalue | Inherit | Return
+            | GetIter | Yield => vec![],
+        }
+    }
+
+    fn upvalue_code_len(&self, closure_offset: usize) -> usize {
+        let value = self
+            .chunk
+            .get_constant(self.closure_constant(closure_offset));
         value.as_function().upvalue_count * 2
     }
 
+    /// The index of the function constant of the `Closure` or `ClosureLong` at `offset`
+    pub fn closure_constant(&self, offset: usize) -> usize {
+        let code = self.chunk.code();
+        if code[offset] == u8::from(OpCode::ClosureLong) {
+            (usize::from(code[offset + 1]) << 16)
+                + (usize::from(code[offset + 2]) << 8)
+                + usize::from(code[offset + 3])
+        } else {
+            usize::from(code[offset + 1])
+        }
+    }
+
     fn debug_constant_opcode(
         &self,
         f: &mut std::fmt::Formatter,
@@ -263,7 +604,7 @@ impl<'chunk> InstructionDisassembler<'chunk> {
         write!(
             f,
             " '{}'",
-            **self.chunk.get_constant(*constant_index.as_ref())
+            *self.chunk.get_constant(*constant_index.as_ref())
         )
     }
 
@@ -284,7 +625,7 @@ impl<'chunk> InstructionDisassembler<'chunk> {
             "{:-16} {:>4} '{}'",
             name,
             *constant_index,
-            **self.chunk.get_constant(*constant_index.as_ref())
+            *self.chunk.get_constant(*constant_index.as_ref())
         )
     }
 
@@ -320,21 +661,42 @@ impl<'chunk> InstructionDisassembler<'chunk> {
         write!(f, "{:-16} {:>4}", name, slot)
     }
 
-    fn debug_jump_opcode(
+    fn debug_two_bytes_opcode(
         &self,
         f: &mut std::fmt::Formatter,
         name: &str,
         offset: &CodeOffset,
     ) -> std::fmt::Result {
         let code = self.chunk.code();
-        let jump = (usize::from(code[offset.as_ref() + 1]) << 8)
-            + (usize::from(code[offset.as_ref() + 2]));
-        let target = **offset + self.instruction_len(**offset);
-        let target = if OpCode::try_from_primitive(code[**offset]).unwrap() == OpCode::Loop {
-            target - jump
-        } else {
-            target + jump
-        };
+        let (a, b) = (code[**offset + 1], code[**offset + 2]);
+        write!(f, "{:-16} {:>4} {:>4}", name, a, b)
+    }
+
+    fn debug_byte_constant_opcode(
+        &self,
+        f: &mut std::fmt::Formatter,
+        name: &str,
+        offset: &CodeOffset,
+    ) -> std::fmt::Result {
+        let code = self.chunk.code();
+        let (slot, constant) = (code[**offset + 1], code[**offset + 2]);
+        write!(
+            f,
+            "{:-16} {:>4} {:>4} '{}'",
+            name,
+            slot,
+            constant,
+            *self.chunk.get_constant(constant)
+        )
+    }
+
+    fn debug_jump_opcode(
+        &self,
+        f: &mut std::fmt::Formatter,
+        name: &str,
+        offset: &CodeOffset,
+    ) -> std::fmt::Result {
+        let target = self.jump_target(**offset);
         write!(f, "{:-16} {:>4} -> {}", name, **offset, target)
     }
 
@@ -344,13 +706,11 @@ impl<'chunk> InstructionDisassembler<'chunk> {
         name: &str,
         offset: &CodeOffset,
     ) -> std::fmt::Result {
-        let mut offset = **offset + 1;
-
         let code = self.chunk.code();
-        let constant = code[offset];
-        offset += 1;
+        let constant = self.closure_constant(**offset);
+        let mut offset = **offset + if name == "OP_CLOSURE_LONG" { 4 } else { 2 };
 
-        let value = &**self.chunk.get_constant(constant);
+        let value = self.chunk.get_constant(constant);
         write!(f, "{:-16} {:>4} {}", name, constant, value)?;
 
         let function = value.as_function();
@@ -388,7 +748,25 @@ impl<'chunk> InstructionDisassembler<'chunk> {
         let code = self.chunk.code();
         let constant = code[offset.as_ref() + 1];
         let arg_count = code[offset.as_ref() + 2];
-        let constant_value = &**self.chunk.get_constant(constant);
+        let constant_value = self.chunk.get_constant(constant);
+        write!(
+            f,
+            "{name:-16} ({arg_count} args) {constant:4} {constant_value}"
+        )
+    }
+
+    fn debug_invoke_long_opcode(
+        &self,
+        f: &mut std::fmt::Formatter,
+        name: &str,
+        offset: &CodeOffset,
+    ) -> std::fmt::Result {
+        let code = self.chunk.code();
+        let constant = (usize::from(code[offset.as_ref() + 1]) << 16)
+            + (usize::from(code[offset.as_ref() + 2]) << 8)
+            + (usize::from(code[offset.as_ref() + 3]));
+        let arg_count = code[offset.as_ref() + 4];
+        let constant_value = self.chunk.get_constant(constant);
         write!(
             f,
             "{name:-16} ({arg_count} args) {constant:4} {constant_value}"
@@ -447,7 +825,14 @@ impl<'chunk> std::fmt::Debug for InstructionDisassembler<'chunk> {
                 GetProperty,
                 SetProperty,
                 Method,
+                StaticMethod,
+                Getter,
+                Setter,
+                ConstField,
                 GetSuper,
+                ReturnConstant,
+                Class,
+                Trait,
             ),
             constant_long(
                 ConstantLong,
@@ -455,12 +840,34 @@ impl<'chunk> std::fmt::Debug for InstructionDisassembler<'chunk> {
                 DefineGlobalConstLong,
                 GetGlobalLong,
                 SetGlobalLong,
+                ClassLong,
+                TraitLong,
+                GetPropertyLong,
+                SetPropertyLong,
+                MethodLong,
+                StaticMethodLong,
+                GetterLong,
+                SetterLong,
+                ConstFieldLong,
+                GetSuperLong,
             ),
-            closure(Closure),
-            byte(Call, GetUpvalue, SetUpvalue, GetLocal, SetLocal, Class),
-            byte_long(GetLocalLong, SetLocalLong),
-            jump(Jump, JumpIfFalse, Loop),
+            closure(Closure, ClosureLong),
+            byte(
+                Call,
+                MixIn,
+                GetUpvalue,
+                SetUpvalue,
+                GetLocal,
+                SetLocal,
+                IterHasNext,
+                IterNext
+            ),
+            byte_long(GetLocalLong, SetLocalLong, IterHasNextLong, IterNextLong),
+            jump(Jump, JumpIfFalse, JumpIfFalsePop, Loop),
+            two_bytes(AddLocals),
+            byte_constant(LessLocalConstant),
             invoke(Invoke, SuperInvoke),
+            invoke_long(InvokeLong, SuperInvokeLong),
             simple(
                 Add,
                 CloseUpvalue,
@@ -477,9 +884,12 @@ impl<'chunk> std::fmt::Debug for InstructionDisassembler<'chunk> {
                 Not,
                 Pop,
                 Print,
+                Echo,
                 Return,
                 Subtract,
                 True,
+                GetIter,
+                Yield,
             ),
         )?;
         Ok(())
@@ -504,3 +914,52 @@ impl Chunk {
 fn opcode_size() {
     assert_eq!(std::mem::size_of::<OpCode>(), 1);
 }
+
+#[cfg(test)]
+#[test]
+fn max_stack_depth() {
+    let mut heap = crate::heap::Heap::new(Default::default());
+    let mut chunk = Chunk::new(heap.add_string("test".to_string()));
+    chunk.make_constant(Value::Number(1.0));
+    // if (nil) { 1 + 1; } else { print 1; }
+    let code: [u8; 17] = [
+        OpCode::Nil.into(),
+        OpCode::JumpIfFalse.into(),
+        0,
+        10,
+        OpCode::Pop.into(),
+        OpCode::Constant.into(),
+        0,
+        OpCode::Constant.into(),
+        0,
+        OpCode::Add.into(),
+        OpCode::Pop.into(),
+        OpCode::Jump.into(),
+        0,
+        1,
+        OpCode::Pop.into(),
+        OpCode::Nil.into(),
+        OpCode::Return.into(),
+    ];
+    for byte in code {
+        chunk.write(byte, Line(1));
+    }
+
+    // The callee, the condition, then both operands of `+`
+    assert_eq!(chunk.max_stack_depth(1), 3);
+}
+
+#[cfg(test)]
+#[test]
+fn max_stack_depth_superinstructions() {
+    let mut heap = crate::heap::Heap::new(Default::default());
+    let mut chunk = Chunk::new(heap.add_string("test".to_string()));
+    // return a + a; with `a` the only argument
+    let code: [u8; 4] = [OpCode::AddLocals.into(), 1, 1, OpCode::Return.into()];
+    for byte in code {
+        chunk.write(byte, Line(1));
+    }
+
+    // The callee, the argument, then both operands pushed if `a` has `__add__`
+    assert_eq!(chunk.max_stack_depth(2), 4);
+}
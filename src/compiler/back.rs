@@ -1,5 +1,5 @@
 use crate::{
-    chunk::{CodeOffset, OpCode},
+    chunk::{CodeOffset, ConstantLongIndex, OpCode},
     scanner::{Token, TokenKind},
     value::Value,
 };
@@ -30,10 +30,10 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     }
 
     pub(super) fn emit_return(&mut self) {
-        if self.function_type() == FunctionType::Initializer {
-            self.emit_bytes(OpCode::GetLocal, 0);
-        } else {
-            self.emit_byte(OpCode::Nil);
+        match self.function_type() {
+            FunctionType::Initializer => self.emit_bytes(OpCode::GetLocal, 0),
+            FunctionType::Setter => self.emit_bytes(OpCode::GetLocal, 1),
+            _ => self.emit_byte(OpCode::Nil),
         }
         self.emit_byte(OpCode::Return);
     }
@@ -43,8 +43,11 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         T: Into<Value>,
     {
         let line = self.line();
-        let value_id = self.heap.add_value(value.into());
-        if !self.current_chunk().write_constant(value_id, line) {
+        let std_mode = self.config.std_mode;
+        if !self
+            .current_chunk()
+            .write_constant(value.into(), line, std_mode)
+        {
             self.error("Too many constants in one chunk.");
         }
     }
@@ -84,6 +87,20 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         self.emit_byte(offset as u8);
     }
 
+    /// Emits `op` with `constant` as its first operand, using the long form of `op` if the index
+    /// doesn't fit in a byte (except in standards mode, where that's a compile error)
+    pub(super) fn emit_constant_op(&mut self, op: OpCode, constant: ConstantLongIndex) {
+        let long = *constant > usize::from(u8::MAX);
+        if long && self.config.std_mode {
+            self.error("Too many constants in one chunk.");
+            return;
+        }
+        self.emit_byte(if long { op.to_long() } else { op });
+        if !self.emit_number(*constant, long) {
+            self.error("Too many constants in one chunk.");
+        }
+    }
+
     pub(super) fn emit_number(&mut self, n: usize, long: bool) -> bool {
         if long {
             self.emit_24bit_number(n)
@@ -107,4 +124,13 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             line: self.line(),
         }
     }
+
+    /// The name of a local the compiler introduces, which can't clash with any identifier
+    pub(super) fn hidden_token(&self, name: &'static str) -> Token<'scanner> {
+        Token {
+            kind: TokenKind::Identifier,
+            lexeme: name.as_bytes(),
+            line: self.line(),
+        }
+    }
 }
@@ -12,11 +12,21 @@ impl<'compiler, 'arena> Compiler<'compiler, 'arena> {
         self.error_at(self.previous.clone(), msg);
     }
 
-    fn error_at(&mut self, token: Option<Token>, msg: &str) {
+    pub(super) fn error_at(&mut self, token: Option<Token>, msg: &str) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
+        if !self.had_error {
+            self.ends_early = token.as_ref().is_some_and(|token| {
+                token.kind == TK::Eof
+                    || (token.kind == TK::Error && token.as_str() == "Unterminated string.")
+            });
+        }
+        if self.quiet {
+            self.had_error = true;
+            return;
+        }
         if let Some(token) = token.as_ref() {
             let line = *token.line;
             let at = if token.kind == TK::Eof {
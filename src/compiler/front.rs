@@ -1,6 +1,6 @@
 use super::{rules::Precedence, ClassState, Compiler, FunctionType, LoopState};
 use crate::{
-    chunk::{CodeOffset, ConstantIndex, OpCode},
+    chunk::{CodeOffset, OpCode},
     scanner::TokenKind as TK,
     types::Line,
 };
@@ -52,6 +52,37 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             .unwrap_or_else(|| false)
     }
 
+    /// Whether the current token is the identifier `keyword`, and the one after it is an
+    /// identifier too. That's how `trait` declarations start, while `trait` is still usable as a
+    /// name everywhere else.
+    pub(super) fn check_contextual_keyword(&self, keyword: &str) -> bool {
+        self.check(TK::Identifier)
+            && self.current.as_ref().unwrap().lexeme == keyword.as_bytes()
+            && self.scanner.clone().scan().kind == TK::Identifier
+    }
+
+    /// Whether the current token is the identifier `keyword` starting a statement rather than an
+    /// expression: the token after it is `;` or can only start an operand, so it can't be a
+    /// variable of that name. That's how `break` and `yield` work.
+    fn check_statement_keyword(&self, keyword: &str) -> bool {
+        self.check(TK::Identifier)
+            && self.current.as_ref().unwrap().lexeme == keyword.as_bytes()
+            && matches!(
+                self.scanner.clone().scan().kind,
+                TK::Semicolon
+                    | TK::Identifier
+                    | TK::Number
+                    | TK::String
+                    | TK::True
+                    | TK::False
+                    | TK::Nil
+                    | TK::This
+                    | TK::Super
+                    | TK::Fun
+                    | TK::Bang
+            )
+    }
+
     pub(super) fn check_previous(&self, kind: TK) -> bool {
         self.previous
             .as_ref()
@@ -76,6 +107,14 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         let nested_state = self.nested(function_name, function_type, |compiler| {
             compiler.begin_scope();
 
+            // Getters have no parameter list
+            if function_type == FunctionType::Getter {
+                compiler.consume(TK::LeftBrace, "Expect '{' before getter body.");
+                compiler.block();
+                compiler.end();
+                return;
+            }
+
             compiler.consume(TK::LeftParen, "Expect '(' after function name.");
 
             if !compiler.check(TK::RightParen) {
@@ -93,6 +132,9 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             }
 
             compiler.consume(TK::RightParen, "Expect ')' after parameters.");
+            if function_type == FunctionType::Setter && compiler.current_function().arity != 1 {
+                compiler.error("A setter must have exactly one parameter.");
+            }
             compiler.consume(TK::LeftBrace, "Expect '{' before function body.");
             compiler.block();
             compiler.end();
@@ -100,49 +142,112 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         let nested_function = nested_state.current_function;
         let nested_upvalues = nested_state.upvalues;
 
-        self.emit_byte(OpCode::Closure);
         let function_id = self.heap.add_function(nested_function);
-        let value_id = self.heap.add_value(function_id.into());
-        let value_id_byte = u8::try_from(self.current_chunk().make_constant(value_id).0).unwrap();
-        self.emit_byte(value_id_byte);
+        let constant = self.current_chunk().make_constant(function_id.into());
+        self.emit_constant_op(OpCode::Closure, constant);
 
         for upvalue in nested_upvalues {
             self.emit_bytes(upvalue.is_local, upvalue.index);
         }
     }
 
-    fn method(&mut self) {
+    fn method(&mut self, class_name: &str) {
+        if !self.config.std_mode && self.match_(TK::Const) {
+            self.const_fields();
+            return;
+        }
+        let is_static = !self.config.std_mode && self.match_(TK::Class);
+        if is_static && self.match_(TK::Var) {
+            self.class_field(class_name);
+            return;
+        }
+
         self.consume(TK::Identifier, "Expect method name.");
+        // `get` and `set` are only accessor keywords when followed by a name, so methods can still
+        // be called that
+        let accessor = match self.previous.as_ref().unwrap().lexeme {
+            b"get" if !self.config.std_mode && self.check(TK::Identifier) => Some(OpCode::Getter),
+            b"set" if !self.config.std_mode && self.check(TK::Identifier) => Some(OpCode::Setter),
+            _ => None,
+        };
+        if accessor.is_some() {
+            if is_static {
+                self.error("Getters and setters can't be class methods.");
+            }
+            self.advance();
+        }
+
         let name_constant =
             self.identifier_constant(self.previous.as_ref().unwrap().as_str().to_string());
-        let function_type = if self.previous.as_ref().unwrap().lexeme == "init".as_bytes() {
-            FunctionType::Initializer
-        } else {
-            FunctionType::Method
+        let (function_type, opcode) = match accessor {
+            Some(OpCode::Getter) => (FunctionType::Getter, OpCode::Getter),
+            Some(_) => (FunctionType::Setter, OpCode::Setter),
+            None if is_static => (FunctionType::Method, OpCode::StaticMethod),
+            None if self.previous.as_ref().unwrap().lexeme == "init".as_bytes() => {
+                (FunctionType::Initializer, OpCode::Method)
+            }
+            None => (FunctionType::Method, OpCode::Method),
         };
         self.function(function_type);
-        self.emit_bytes(
-            OpCode::Method,
-            ConstantIndex::try_from(name_constant)
-                .expect("Too many constants when declaring method."),
-        );
+        self.emit_constant_op(opcode, name_constant);
+    }
+
+    /// `class var name = value;` in a class body: sets a field on the class
+    fn class_field(&mut self, class_name: &str) {
+        self.consume(TK::Identifier, "Expect field name.");
+        let name_constant =
+            self.identifier_constant(self.previous.as_ref().unwrap().as_str().to_string());
+        self.named_variable(class_name, false);
+        if self.match_(TK::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.consume(TK::Semicolon, "Expect ';' after field declaration.");
+        self.emit_constant_op(OpCode::SetProperty, name_constant);
+        self.emit_byte(OpCode::Pop);
     }
 
-    fn class_declaration(&mut self) {
-        self.consume(TK::Identifier, "Expect class name.");
+    /// `const a, b;` in a class body: instance fields that can only be set in `init`
+    fn const_fields(&mut self) {
+        loop {
+            self.consume(TK::Identifier, "Expect field name.");
+            let name_constant =
+                self.identifier_constant(self.previous.as_ref().unwrap().as_str().to_string());
+            self.emit_constant_op(OpCode::ConstField, name_constant);
+            if self.match_(TK::Equal) {
+                self.error("Const fields can only be set in 'init'.");
+                // Parsed anyway, to carry on after the error
+                self.expression();
+            }
+            if !self.match_(TK::Comma) {
+                break;
+            }
+        }
+        self.consume(TK::Semicolon, "Expect ';' after const field declaration.");
+    }
+
+    /// Also compiles `trait` declarations, which are classes that can only be mixed in
+    fn class_declaration(&mut self, is_trait: bool) {
+        if is_trait {
+            self.consume(TK::Identifier, "Expect trait name.");
+        } else {
+            self.consume(TK::Identifier, "Expect class name.");
+        }
         let class_name = self.previous.as_ref().unwrap().as_str().to_string();
         let name_constant = self.identifier_constant(class_name.to_string());
         self.declare_variable(true);
 
-        self.emit_bytes(
-            OpCode::Class,
-            ConstantIndex::try_from(name_constant)
-                .expect("Too many constants when declaring class."),
-        );
+        let opcode = if is_trait {
+            OpCode::Trait
+        } else {
+            OpCode::Class
+        };
+        self.emit_constant_op(opcode, name_constant);
         self.define_variable(Some(name_constant), true);
-        self.class_state.push(ClassState::new());
+        self.class_state.push(ClassState::new(is_trait));
 
-        if self.match_(TK::Less) {
+        if !is_trait && self.match_(TK::Less) {
             self.consume(TK::Identifier, "Expect superclass name.");
             self.variable(false);
 
@@ -159,14 +264,43 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             self.current_class_mut().unwrap().has_superclass = true;
         }
 
-        self.named_variable(class_name, false);
+        // Mixed in after the body, so that the class's own methods are known by then
+        let mut trait_names = vec![];
+        if !self.config.std_mode && self.check_contextual_keyword("with") {
+            self.advance();
+            loop {
+                self.consume(TK::Identifier, "Expect trait name.");
+                let trait_name = self.previous.as_ref().unwrap().as_str().to_string();
+                if class_name == trait_name {
+                    self.error("A class can't mix in itself.");
+                }
+                if trait_names.len() == usize::from(u8::MAX) {
+                    self.error("Can't mix in more than 255 traits.");
+                }
+                trait_names.push(trait_name);
+                if !self.match_(TK::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.named_variable(&class_name, false);
         self.consume(TK::LeftBrace, "Expect '{' before class body.");
         while !self.check(TK::RightBrace) && !self.check(TK::Eof) {
-            self.method();
+            self.method(&class_name);
         }
         self.consume(TK::RightBrace, "Expect '}' after class body.");
         self.emit_byte(OpCode::Pop);
 
+        if !trait_names.is_empty() {
+            let trait_count = u8::try_from(trait_names.len()).unwrap_or(u8::MAX);
+            for trait_name in &trait_names {
+                self.named_variable(trait_name, false);
+            }
+            self.named_variable(&class_name, false);
+            self.emit_bytes(OpCode::MixIn, trait_count);
+        }
+
         if self.current_class().unwrap().has_superclass {
             self.end_scope();
         }
@@ -175,9 +309,14 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     }
 
     fn fun_declaration(&mut self) {
+        let function_type = if !self.config.std_mode && self.match_(TK::Star) {
+            FunctionType::Generator
+        } else {
+            FunctionType::Function
+        };
         let global = self.parse_variable("Expect function name.", false);
         self.mark_initialized();
-        self.function(FunctionType::Function);
+        self.function(function_type);
         self.define_variable(global, false);
     }
 
@@ -197,7 +336,11 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     fn expression_statement(&mut self) {
         self.expression();
         self.consume(TK::Semicolon, "Expect ';' after expression.");
-        self.emit_byte(OpCode::Pop);
+        if self.echo && self.function_type() == FunctionType::Script && *self.scope_depth() == 0 {
+            self.emit_byte(OpCode::Echo);
+        } else {
+            self.emit_byte(OpCode::Pop);
+        }
     }
 
     fn for_statement(&mut self) {
@@ -210,9 +353,12 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             None
         } else if self.match_(TK::Var) || self.match_(TK::Const) {
             let name = self.current.clone().unwrap();
+            if !self.config.std_mode && self.for_in_statement() {
+                return;
+            }
             self.var_declaration(self.check_previous(TK::Var));
             // Challenge 25/2: alias loop variables
-            if crate::config::STD_MODE.load() {
+            if self.config.std_mode {
                 None
             } else if let Ok(loop_var) = u8::try_from(self.locals().len() - 1) {
                 Some((loop_var, name))
@@ -226,11 +372,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         };
 
         // Store old loop state to restore at then end
-        let old_loop_state = {
-            let start = CodeOffset(self.current_chunk_len());
-            let depth = self.scope_depth();
-            std::mem::replace(self.loop_state_mut(), Some(LoopState { depth, start }))
-        };
+        let old_loop_state = self.begin_loop();
 
         // Compile loop condition
         let mut exit_jump = None;
@@ -249,9 +391,10 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             self.emit_byte(OpCode::Pop);
             self.consume(TK::RightParen, "Expect ')' after for clauses.");
 
-            let loop_start = self.loop_state().as_ref().unwrap().start;
+            let loop_start = self.loop_start();
             self.emit_loop(loop_start);
-            self.loop_state_mut().as_mut().unwrap().start = increment_start;
+            let continue_to = &mut self.loop_state_mut().as_mut().unwrap().continue_to;
+            continue_to.as_mut().unwrap().1 = increment_start;
             self.patch_jump(body_jump);
         }
 
@@ -281,7 +424,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             self.end_scope();
         }
 
-        let loop_start = self.loop_state().as_ref().unwrap().start;
+        let loop_start = self.loop_start();
         self.emit_loop(loop_start);
 
         if let Some(exit_jump) = exit_jump {
@@ -289,10 +432,115 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             self.emit_byte(OpCode::Pop);
         }
 
-        *self.loop_state_mut() = old_loop_state;
+        self.end_loop(old_loop_state);
         self.end_scope();
     }
 
+    /// `for (var x in iterable)`, after `var`: returns `false` without consuming anything if it's
+    /// a C-style `for` instead. Desugared into hidden locals like `for_statement`:
+    ///
+    /// ```text
+    /// {
+    ///   var iterator = GET_ITER(iterable);
+    ///   var position = 0; // Only used for built-in iterables
+    ///   while (ITER_HAS_NEXT iterator) {
+    ///     var x = ITER_NEXT iterator;
+    ///     body
+    ///   }
+    /// }
+    /// ```
+    fn for_in_statement(&mut self) -> bool {
+        if !self.check(TK::Identifier) {
+            return false;
+        }
+        let mut lookahead = self.scanner.clone();
+        if lookahead.scan().lexeme != b"in" {
+            return false;
+        }
+        let mutable = self.check_previous(TK::Var);
+        self.advance();
+        let name = self.previous.clone().unwrap();
+        self.advance(); // in
+
+        self.expression();
+        self.consume(TK::RightParen, "Expect ')' after for-in clauses.");
+        self.emit_byte(OpCode::GetIter);
+        self.add_local(self.hidden_token("(for-in iterator)"), false);
+        self.mark_initialized();
+        let iterator = self.locals().len() - 1;
+        self.emit_constant(0.0);
+        self.add_local(self.hidden_token("(for-in position)"), false);
+        self.mark_initialized();
+
+        let old_loop_state = self.begin_loop();
+        self.emit_iterator_op(OpCode::IterHasNext, iterator);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+
+        // A new loop variable for each iteration, so closures capture only that iteration's value
+        self.begin_scope();
+        self.emit_iterator_op(OpCode::IterNext, iterator);
+        self.add_local(name, mutable);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+
+        let loop_start = self.loop_start();
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop);
+
+        self.end_loop(old_loop_state);
+        self.end_scope();
+        true
+    }
+
+    /// Emits `op` for the iterator in local `slot`, using its long form past `u8::MAX`
+    fn emit_iterator_op(&mut self, op: OpCode, slot: usize) {
+        let long = slot > usize::from(u8::MAX);
+        self.emit_byte(if long { op.to_long() } else { op });
+        if !self.emit_number(slot, long) {
+            self.error("Too many local variables in function.");
+        }
+    }
+
+    /// Saves the state of the enclosing loop, if any, for `end_loop`. `continue` jumps back to
+    /// here, unless the caller moves `start` later.
+    fn begin_loop(&mut self) -> Option<LoopState> {
+        let depth = self.scope_depth();
+        let state = LoopState {
+            depth,
+            break_jumps: vec![],
+            continue_to: Some((depth, CodeOffset(self.current_chunk_len()))),
+        };
+        std::mem::replace(self.loop_state_mut(), Some(state))
+    }
+
+    /// Like `begin_loop`, but `continue` still goes to the enclosing loop
+    fn begin_switch(&mut self) -> Option<LoopState> {
+        let state = LoopState {
+            depth: self.scope_depth(),
+            break_jumps: vec![],
+            continue_to: self.loop_state().as_ref().and_then(|state| state.continue_to),
+        };
+        std::mem::replace(self.loop_state_mut(), Some(state))
+    }
+
+    /// Where the innermost loop's next iteration starts
+    fn loop_start(&mut self) -> CodeOffset {
+        self.loop_state().as_ref().unwrap().continue_to.unwrap().1
+    }
+
+    /// Points the `break`s of the loop or `switch` here, and restores the state of the enclosing
+    /// one
+    fn end_loop(&mut self, old_loop_state: Option<LoopState>) {
+        let loop_state = std::mem::replace(self.loop_state_mut(), old_loop_state)
+            .expect("end_loop without begin_loop");
+        for jump in loop_state.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
     fn if_statement(&mut self) {
         self.consume(TK::LeftParen, "Expect '(' after 'if'.");
         self.expression();
@@ -313,11 +561,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     }
 
     fn while_statement(&mut self) {
-        let old_loop_state = {
-            let start = CodeOffset(self.current_chunk_len());
-            let depth = self.scope_depth();
-            std::mem::replace(self.loop_state_mut(), Some(LoopState { depth, start }))
-        };
+        let old_loop_state = self.begin_loop();
         self.consume(TK::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TK::RightParen, "Expect ')' after condition.");
@@ -325,19 +569,24 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop);
         self.statement();
-        let loop_start = self.loop_state().as_ref().unwrap().start;
+        let loop_start = self.loop_start();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::Pop);
-        *self.loop_state_mut() = old_loop_state;
+        self.end_loop(old_loop_state);
     }
 
     fn switch_statement(&mut self) {
         self.consume(TK::LeftParen, "Expect '(' after 'switch'.");
+        // A hidden local, so `continue` in the switch pops it
+        self.begin_scope();
         self.expression();
+        self.add_local(self.hidden_token("(switch value)"), false);
+        self.mark_initialized();
         self.consume(TK::RightParen, "Expect ')' after 'switch' value.");
         self.consume(TK::LeftBrace, "Expect '{' before 'switch' body.");
+        let old_loop_state = self.begin_switch();
 
         let mut end_jumps = vec![];
         let mut had_default = false;
@@ -377,35 +626,46 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         for end_jump in end_jumps {
             self.patch_jump(end_jump);
         }
-        self.emit_byte(OpCode::Pop); // Get rid of the switch value
+        self.end_loop(old_loop_state);
 
         self.consume(TK::RightBrace, "Expect '}' after 'switch' body.");
+        self.end_scope(); // Get rid of the switch value
     }
 
     fn continue_statement(&mut self) {
-        match *self.loop_state() {
+        match self.loop_state().as_ref().and_then(|state| state.continue_to) {
             None => self.error("'continue' outside a loop."),
-            Some(state) => {
+            Some((depth, start)) => {
                 self.consume(TK::Semicolon, "Expect ';' after 'continue'.");
+                self.discard_locals(depth);
+                self.emit_loop(start);
+            }
+        }
+    }
 
-                let locals_to_drop = self
-                    .locals()
-                    .iter()
-                    .rev()
-                    .take_while(|local| local.depth > state.depth)
-                    .count();
-                for _ in 0..locals_to_drop {
-                    self.emit_byte(OpCode::Pop);
-                }
-
-                self.emit_loop(state.start);
+    fn break_statement(&mut self) {
+        match self.loop_state() {
+            None => self.error("'break' outside a loop or 'switch'."),
+            Some(state) => {
+                let depth = state.depth;
+                self.consume(TK::Semicolon, "Expect ';' after 'break'.");
+                self.discard_locals(depth);
+                let jump = self.emit_jump(OpCode::Jump);
+                self.loop_state_mut()
+                    .as_mut()
+                    .unwrap()
+                    .break_jumps
+                    .push(jump);
             }
         }
     }
 
     pub(super) fn declaration(&mut self) {
         if self.match_(TK::Class) {
-            self.class_declaration();
+            self.class_declaration(false);
+        } else if !self.config.std_mode && self.check_contextual_keyword("trait") {
+            self.advance();
+            self.class_declaration(true);
         } else if self.match_(TK::Fun) {
             self.fun_declaration();
         } else if self.match_(TK::Var) {
@@ -436,6 +696,18 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             self.switch_statement();
         } else if self.match_(TK::Continue) {
             self.continue_statement();
+        } else if !self.config.std_mode && self.check_statement_keyword("break") {
+            self.advance();
+            self.break_statement();
+        } else if !self.config.std_mode
+            && (self.check_statement_keyword("yield")
+                // So that `yield (a);` and `yield -a;` aren't a call and a subtraction
+                || (self.function_type() == FunctionType::Generator
+                    && self.check(TK::Identifier)
+                    && self.current.as_ref().unwrap().lexeme == b"yield"))
+        {
+            self.advance();
+            self.yield_statement();
         } else if self.match_(TK::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -458,12 +730,32 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         if self.match_(TK::Semicolon) {
             self.emit_return();
         } else {
-            if self.function_type() == FunctionType::Initializer {
-                self.error("Can't return a value from an initializer.");
+            match self.function_type() {
+                FunctionType::Initializer => {
+                    self.error("Can't return a value from an initializer.");
+                }
+                FunctionType::Setter => self.error("Can't return a value from a setter."),
+                FunctionType::Generator => {
+                    self.error("Can't return a value from a generator.");
+                }
+                _ => {}
             }
             self.expression();
             self.consume(TK::Semicolon, "Expect ';' after return value.");
             self.emit_byte(OpCode::Return);
         }
     }
+
+    fn yield_statement(&mut self) {
+        if self.function_type() != FunctionType::Generator {
+            self.error("Can't use 'yield' outside a generator.");
+        }
+        if self.match_(TK::Semicolon) {
+            self.emit_byte(OpCode::Nil);
+        } else {
+            self.expression();
+            self.consume(TK::Semicolon, "Expect ';' after yielded value.");
+        }
+        self.emit_byte(OpCode::Yield);
+    }
 }
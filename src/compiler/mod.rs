@@ -5,14 +5,16 @@ mod rules;
 mod variables;
 
 use log::debug;
-use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use shrinkwraprs::Shrinkwrap;
 
 use crate::{
     chunk::{Chunk, CodeOffset, ConstantLongIndex},
     compiler::rules::{make_rules, Rules},
-    config,
+    config::{GcConfig, VmConfig},
     heap::{Heap, StringId},
+    native_functions::Capability,
+    optimizer,
     scanner::{Scanner, Token, TokenKind},
     types::Line,
     value::Function,
@@ -35,13 +37,23 @@ enum FunctionType {
     Function,
     Initializer,
     Method,
+    Getter,
+    /// Returns its argument, so assigning through it evaluates to the assigned value
+    Setter,
+    /// Declared with `fun*`; calling it creates a generator instead of running the body
+    Generator,
     Script,
 }
 
-#[derive(Copy, Clone)]
+/// The innermost loop or `switch`
+#[derive(Clone)]
 struct LoopState {
     depth: ScopeDepth,
-    start: CodeOffset,
+    /// Patched to jump past the end of the loop or `switch` when it's done
+    break_jumps: Vec<CodeOffset>,
+    /// The scope depth and offset `continue` jumps back to. A `switch` has the enclosing loop's,
+    /// if there is one.
+    continue_to: Option<(ScopeDepth, CodeOffset)>,
 }
 
 #[derive(Clone, Debug)]
@@ -64,13 +76,15 @@ struct NestableState<'scanner> {
 
 struct ClassState {
     pub has_superclass: bool,
+    pub is_trait: bool,
 }
 
 impl ClassState {
     #[must_use]
-    fn new() -> Self {
+    fn new(is_trait: bool) -> Self {
         Self {
             has_superclass: false,
+            is_trait,
         }
     }
 }
@@ -78,15 +92,21 @@ impl ClassState {
 impl<'scanner> NestableState<'scanner> {
     #[must_use]
     fn new(function_name: StringId, function_type: FunctionType) -> Self {
+        let mut current_function = Function::new(0, function_name);
+        current_function.is_generator = function_type == FunctionType::Generator;
         NestableState {
-            current_function: Function::new(0, function_name),
+            current_function,
             function_type,
             locals: vec![Local {
                 name: Token {
                     kind: TokenKind::Identifier,
-                    lexeme: if function_type == FunctionType::Method
-                        || function_type == FunctionType::Initializer
-                    {
+                    lexeme: if matches!(
+                        function_type,
+                        FunctionType::Method
+                            | FunctionType::Initializer
+                            | FunctionType::Getter
+                            | FunctionType::Setter
+                    ) {
                         "this".as_bytes()
                     } else {
                         &[]
@@ -105,9 +125,24 @@ impl<'scanner> NestableState<'scanner> {
     }
 }
 
+/// See `Compiler::input_status`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputStatus {
+    Complete,
+    Incomplete,
+    Invalid,
+}
+
 pub struct Compiler<'scanner, 'heap> {
     heap: &'heap mut Heap,
-    strings_by_name: HashMap<String, StringId>,
+    config: VmConfig,
+
+    /// Natives the `VM` wasn't granted, and the capability each one needs
+    denied_natives: HashMap<String, Capability>,
+    /// Uses of globals named like a denied native. They're errors unless the script declares a
+    /// global of that name somewhere, which is only known at the end.
+    denied_native_uses: Vec<(Token<'scanner>, String, Capability)>,
+    declared_globals: HashSet<String>,
 
     rules: Rules<'scanner, 'heap>,
 
@@ -117,6 +152,11 @@ pub struct Compiler<'scanner, 'heap> {
 
     had_error: bool,
     panic_mode: bool,
+    /// See `set_echo`
+    echo: bool,
+    /// For `input_status`: don't report errors, just note whether the first one was at the end
+    quiet: bool,
+    ends_early: bool,
 
     nestable_state: Vec<NestableState<'scanner>>,
     class_state: Vec<ClassState>,
@@ -124,21 +164,23 @@ pub struct Compiler<'scanner, 'heap> {
 
 impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     #[must_use]
-    pub fn new(scanner: Scanner<'scanner>, heap: &'heap mut Heap) -> Self {
+    pub fn new(scanner: Scanner<'scanner>, heap: &'heap mut Heap, config: &VmConfig) -> Self {
         let function_name = heap.add_string(String::from("<script>"));
 
-        let mut strings_by_name: HashMap<String, StringId> = HashMap::default();
-        let init_string = heap.builtin_constants().init_string;
-        strings_by_name.insert(init_string.to_string(), init_string);
-
         Compiler {
             heap,
-            strings_by_name,
+            config: config.clone(),
+            denied_natives: HashMap::default(),
+            denied_native_uses: vec![],
+            declared_globals: HashSet::default(),
             scanner,
             previous: None,
             current: None,
             had_error: false,
             panic_mode: false,
+            echo: false,
+            quiet: false,
+            ends_early: false,
             rules: make_rules(),
             nestable_state: vec![NestableState::new(function_name, FunctionType::Script)],
             class_state: vec![],
@@ -191,6 +233,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         }
 
         self.end();
+        self.check_denied_natives();
         if self.had_error {
             None
         } else {
@@ -198,10 +241,42 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         }
     }
 
+    /// Whether `source` compiles, or is the start of a program rather than a wrong one: its first
+    /// compile error is at the end, or is an unterminated string. The REPL asks for more lines if so.
+    pub fn input_status(source: &[u8]) -> InputStatus {
+        let mut heap = Heap::new(GcConfig::default());
+        let mut compiler = Compiler::new(Scanner::new(source), &mut heap, &VmConfig::default());
+        compiler.quiet = true;
+        compiler.advance();
+        while !compiler.match_(TokenKind::Eof) {
+            compiler.declaration();
+        }
+        if !compiler.had_error {
+            InputStatus::Complete
+        } else if compiler.ends_early {
+            InputStatus::Incomplete
+        } else {
+            InputStatus::Invalid
+        }
+    }
+
     fn end(&mut self) {
         self.emit_return();
 
-        if config::PRINT_CODE.load() && !self.had_error {
+        if self.config.optimize {
+            let chunk = &mut self
+                .nestable_state
+                .last_mut()
+                .unwrap()
+                .current_function
+                .chunk;
+            optimizer::optimize(chunk, self.heap, self.config.std_mode);
+        }
+
+        let function = self.current_function_mut();
+        function.max_stack_depth = function.chunk.max_stack_depth(function.arity + 1);
+
+        if self.config.print_code && !self.had_error {
             debug!("{:?}", self.current_chunk());
         }
     }
@@ -266,10 +341,13 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         self.current_chunk().code().len()
     }
 
-    pub fn inject_strings(&mut self, names: &HashMap<String, StringId>) {
-        for (key, value) in names {
-            self.strings_by_name.insert(key.clone(), *value);
-        }
+    /// Makes top-level expression statements print their value (unless it's `nil`), like in the REPL
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    pub fn deny_natives(&mut self, natives: HashMap<String, Capability>) {
+        self.denied_natives = natives;
     }
 
     fn current_class(&self) -> Option<&ClassState> {
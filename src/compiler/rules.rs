@@ -197,22 +197,13 @@ impl<'scanner, 'arena> Compiler<'scanner, 'arena> {
 
         if can_assign && self.match_(TK::Equal) {
             self.expression();
-            self.emit_byte(OpCode::SetProperty);
-            if !self.emit_number(name_constant.0, false) {
-                self.error("Too many constants created for OP_SET_PROPERTY.");
-            }
+            self.emit_constant_op(OpCode::SetProperty, name_constant);
         } else if self.match_(TK::LeftParen) {
             let arg_count = self.argument_list();
-            self.emit_byte(OpCode::Invoke);
-            if !self.emit_number(name_constant.0, false) {
-                self.error("Too many constants created for OP_INVOKE.");
-            }
+            self.emit_constant_op(OpCode::Invoke, name_constant);
             self.emit_byte(arg_count);
         } else {
-            self.emit_byte(OpCode::GetProperty);
-            if !self.emit_number(name_constant.0, false) {
-                self.error("Too many constants created for OP_GET_PROPERTY.");
-            }
+            self.emit_constant_op(OpCode::GetProperty, name_constant);
         }
     }
 
@@ -273,6 +264,9 @@ impl<'scanner, 'arena> Compiler<'scanner, 'arena> {
             None => {
                 self.error("Can't use 'super' outside of a class.");
             }
+            Some(class) if class.is_trait => {
+                self.error("Can't use 'super' in a trait.");
+            }
             Some(class) if !class.has_superclass => {
                 self.error("Can't use 'super' in a class with no superclass.");
             }
@@ -286,17 +280,11 @@ impl<'scanner, 'arena> Compiler<'scanner, 'arena> {
         if self.match_(TK::LeftParen) {
             let arg_count = self.argument_list();
             self.named_variable(self.synthetic_token(TK::Super).as_str(), false);
-            self.emit_byte(OpCode::SuperInvoke);
-            if !self.emit_number(*name, false) {
-                self.error("Too many constants while compiling OP_SUPER_INVOKE");
-            }
+            self.emit_constant_op(OpCode::SuperInvoke, name);
             self.emit_byte(arg_count);
         } else {
             self.named_variable(self.synthetic_token(TK::Super).as_str(), false);
-            self.emit_byte(OpCode::GetSuper);
-            if !self.emit_number(*name, false) {
-                self.error("Too many constants while compiling OP_GET_SUPER");
-            }
+            self.emit_constant_op(OpCode::GetSuper, name);
         }
     }
 }
@@ -1,8 +1,5 @@
-use std::collections::hash_map::Entry;
-
 use crate::{
     chunk::{ConstantLongIndex, OpCode},
-    config,
     heap::StringId,
 };
 
@@ -41,6 +38,27 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         }
     }
 
+    /// Pops the locals deeper than `depth` off the stack, closing captured ones, but leaves them in
+    /// scope: for `break` and `continue` jumping out of their scopes
+    pub(super) fn discard_locals(&mut self, depth: ScopeDepth) {
+        let instructions = self
+            .locals()
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| {
+                if local.is_captured {
+                    OpCode::CloseUpvalue
+                } else {
+                    OpCode::Pop
+                }
+            })
+            .collect::<Vec<_>>();
+        for instruction in instructions {
+            self.emit_byte(instruction);
+        }
+    }
+
     pub(super) fn variable(&mut self, can_assign: bool) {
         self.named_variable(
             self.previous.as_ref().unwrap().as_str().to_string(),
@@ -67,6 +85,11 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
 
         // If neither local nor upvalue, then it must be a global
         if arg.is_none() {
+            let name = name.to_string();
+            if let Some(capability) = self.denied_natives.get(&name) {
+                let use_ = (self.previous.clone().unwrap(), name.clone(), *capability);
+                self.denied_native_uses.push(use_);
+            }
             arg = Some(*self.identifier_constant(name));
             get_op = OpCode::GetGlobal;
             set_op = OpCode::SetGlobal;
@@ -75,7 +98,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         let arg = arg.unwrap();
 
         // Support for more than u8::MAX variables in a scope
-        let long = if !crate::config::STD_MODE.load() && arg > u8::MAX.into() {
+        let long = if !self.config.std_mode && arg > u8::MAX.into() {
             get_op = get_op.to_long();
             set_op = set_op.to_long();
             true
@@ -105,10 +128,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     where
         S: ToString,
     {
-        match self.strings_by_name.entry(s.to_string()) {
-            Entry::Vacant(entry) => *entry.insert(self.heap.add_string(s.to_string())),
-            Entry::Occupied(entry) => *entry.get(),
-        }
+        self.heap.add_string(s.to_string())
     }
 
     pub(super) fn identifier_constant<S>(&mut self, name: S) -> ConstantLongIndex
@@ -120,8 +140,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         if let Some(index) = self.globals_by_name().get(&string_id) {
             *index
         } else {
-            let value_id = self.heap.add_value(string_id.into());
-            let index = self.current_chunk().make_constant(value_id);
+            let index = self.current_chunk().make_constant(string_id.into());
             self.globals_by_name_mut().insert(string_id, index);
             index
         }
@@ -207,7 +226,7 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
     }
 
     pub(super) fn add_local(&mut self, name: Token<'scanner>, mutable: bool) {
-        let limit_exp = if config::STD_MODE.load() { 8 } else { 24 };
+        let limit_exp = if self.config.std_mode { 8 } else { 24 };
         if self.locals().len() > usize::pow(2, limit_exp) - 1 {
             self.error("Too many local variables in function.");
             return;
@@ -267,6 +286,8 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
             return;
         }
         let global = global.unwrap();
+        let name = self.current_chunk().get_constant(*global).to_string();
+        self.declared_globals.insert(name);
 
         if let Ok(short) = u8::try_from(*global) {
             if mutable {
@@ -307,6 +328,19 @@ impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
         arg_count
     }
 
+    /// Reports the uses of denied natives that can't refer to a global declared by the script
+    pub(super) fn check_denied_natives(&mut self) {
+        for (token, name, capability) in std::mem::take(&mut self.denied_native_uses) {
+            if !self.declared_globals.contains(&name) {
+                self.panic_mode = false;
+                self.error_at(
+                    Some(token),
+                    &format!("Native function needs the '{}' capability.", capability),
+                );
+            }
+        }
+    }
+
     fn check_local_const(&mut self, local_index: usize) {
         let local = &self.locals()[local_index];
         if *local.depth != -1 && !local.mutable {
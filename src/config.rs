@@ -1,32 +1,68 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::native_functions::Capabilities;
 
 pub const FRAMES_MAX: usize = 64;
 pub const STACK_MAX: usize = FRAMES_MAX * 256;
 pub const GC_HEAP_GROW_FACTOR: usize = 2;
+pub const GC_INITIAL_HEAP_SIZE: usize = 1024 * 1024;
+/// How many gray objects are traced between two instructions while a GC cycle is marking
+pub const GC_STEP: usize = 64;
+/// Same as `GC_STEP`, with `--stress-gc`: as small as possible, to interleave marking with as much
+/// mutation as possible
+pub const GC_STRESS_STEP: usize = 1;
+/// How many instructions are executed between two checks of the timeout and the cancellation flag
+pub const LIMIT_CHECK_INTERVAL: u64 = 1024;
 
-pub struct GlobalFlag {
-    value: AtomicBool,
+/// Everything that can be set for a `VM`, and the compiler and heap it uses. Each `VM` has its own,
+/// so differently configured ones can run side by side.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmConfig {
+    /// Standards mode: compatibility with standard `clox`
+    pub std_mode: bool,
+    pub trace_execution: bool,
+    /// Print each function's bytecode after compiling it
+    pub print_code: bool,
+    /// Run the peephole optimizer on the compiled bytecode
+    pub optimize: bool,
+    pub gc: GcConfig,
+    /// Only the natives allowed by these are defined
+    pub capabilities: Capabilities,
 }
 
-impl GlobalFlag {
-    #[must_use]
-    pub const fn new() -> GlobalFlag {
-        GlobalFlag {
-            value: AtomicBool::new(false),
-        }
-    }
-
-    pub fn store(&self, value: bool) {
-        self.value.store(value, Ordering::Relaxed);
-    }
+/// GC tuning for a `VM`; the defaults are the constants above, with no memory limit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcConfig {
+    /// Bytes allocated before the first collection
+    pub initial_heap_size: usize,
+    /// After a collection, the next one starts when the heap grows to this multiple of what's left
+    pub heap_grow_factor: usize,
+    /// Allocating past this many bytes, even after a full collection, is an "Out of memory." runtime
+    /// error
+    pub memory_limit: Option<usize>,
+    /// Collect garbage before every instruction
+    pub stress: bool,
+    /// Log each collection and what it does
+    pub log: bool,
+}
 
-    pub fn load(&self) -> bool {
-        self.value.load(Ordering::Relaxed)
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            initial_heap_size: GC_INITIAL_HEAP_SIZE,
+            heap_grow_factor: GC_HEAP_GROW_FACTOR,
+            memory_limit: None,
+            stress: false,
+            log: false,
+        }
     }
 }
 
-pub static STD_MODE: GlobalFlag = GlobalFlag::new();
-pub static TRACE_EXECUTION: GlobalFlag = GlobalFlag::new();
-pub static PRINT_CODE: GlobalFlag = GlobalFlag::new();
-pub static STRESS_GC: GlobalFlag = GlobalFlag::new();
-pub static LOG_GC: GlobalFlag = GlobalFlag::new();
+/// Limits on each `VM::interpret` call; see `VM::set_limits` and `VM::cancellation_handle`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecutionLimits {
+    /// The most instructions to execute
+    pub instruction_budget: Option<u64>,
+    /// Wall-clock time to run for. Not enforced on wasm32, where there's no clock.
+    pub timeout: Option<Duration>,
+}
@@ -0,0 +1,177 @@
+//! `clox-rs disasm`: compiles a script without running it and disassembles every function in it.
+//!
+//! Functions are found through the constants of the chunks they're nested in, so that covers
+//! closures, methods and initializers. The text form is what `--print-code` prints; the JSON form
+//! has one line per instruction, with its operands decoded, for diffing and other tools.
+
+use std::io::{self, Write};
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    chunk::{CodeOffset, InstructionDisassembler, OpCode, Operand},
+    compiler::Compiler,
+    config::VmConfig,
+    heap::Heap,
+    heap_dump::{comma, json_string},
+    scanner::Scanner,
+    value::{Function, Value},
+};
+
+/// Returns `false`, after reporting the errors, if `source` doesn't compile
+pub fn disassemble(
+    source: &[u8],
+    config: &VmConfig,
+    json: bool,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    let mut heap = Heap::new(config.gc.clone());
+    let Some(script) = Compiler::new(Scanner::new(source), &mut heap, config).compile() else {
+        return Ok(false);
+    };
+    let functions = functions(&script);
+    if json {
+        write_json(&functions, out)?;
+    } else {
+        for (i, function) in functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(out)?;
+            }
+            write!(out, "{:?}", function.chunk)?;
+        }
+    }
+    Ok(true)
+}
+
+/// `script` and every function nested in it, depth first in the order they appear in constants
+pub fn functions(script: &Function) -> Vec<&Function> {
+    fn visit<'f>(function: &'f Function, functions: &mut Vec<&'f Function>) {
+        functions.push(function);
+        for constant in function.chunk.constants() {
+            if let Value::Function(nested) = constant {
+                visit(nested, functions);
+            }
+        }
+    }
+
+    let mut functions = vec![];
+    visit(script, &mut functions);
+    functions
+}
+
+fn write_json(functions: &[&Function], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"functions\": [")?;
+    for (i, function) in functions.iter().enumerate() {
+        let chunk = &function.chunk;
+        writeln!(out, "    {{")?;
+        writeln!(out, "      \"name\": {},", json_string(&function.name))?;
+        writeln!(out, "      \"arity\": {},", function.arity)?;
+        writeln!(out, "      \"upvalueCount\": {},", function.upvalue_count)?;
+        writeln!(
+            out,
+            "      \"maxStackDepth\": {},",
+            function.max_stack_depth
+        )?;
+
+        writeln!(out, "      \"constants\": [")?;
+        let constants = chunk.constants();
+        for (index, value) in constants.iter().enumerate() {
+            writeln!(
+                out,
+                "        {{{}}}{}",
+                constant_fields(index, value),
+                comma(index, constants.len())
+            )?;
+        }
+        writeln!(out, "      ],")?;
+
+        writeln!(out, "      \"code\": [")?;
+        let mut disassembler = InstructionDisassembler::new(chunk);
+        while *disassembler.offset < chunk.code().len() {
+            let offset = *disassembler.offset;
+            let opcode = OpCode::try_from_primitive(chunk.code()[offset]).unwrap();
+            let operands = disassembler
+                .operands()
+                .into_iter()
+                .map(|operand| match operand {
+                    Operand::Byte(value) => format!("{{\"kind\": \"byte\", \"value\": {}}}", value),
+                    Operand::Constant(index) => format!(
+                        "{{\"kind\": \"constant\", {}}}",
+                        constant_fields(index, chunk.get_constant(index))
+                    ),
+                    Operand::Jump(target) => {
+                        format!("{{\"kind\": \"jump\", \"target\": {}}}", target)
+                    }
+                    Operand::Upvalue { is_local, index } => format!(
+                        "{{\"kind\": \"upvalue\", \"isLocal\": {}, \"index\": {}}}",
+                        is_local, index
+                    ),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            *disassembler.offset += disassembler.instruction_len(offset);
+            writeln!(
+                out,
+                "        {{\"offset\": {}, \"line\": {}, \"opcode\": {}, \"operands\": [{}]}}{}",
+                offset,
+                *chunk.get_line(&CodeOffset(offset)),
+                json_string(&opcode.name()),
+                operands,
+                if *disassembler.offset < chunk.code().len() {
+                    ","
+                } else {
+                    ""
+                }
+            )?;
+        }
+        writeln!(out, "      ]")?;
+        writeln!(out, "    }}{}", comma(i, functions.len()))?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+fn constant_fields(index: usize, value: &Value) -> String {
+    let type_name = match value {
+        Value::Bool(_) => "Bool",
+        Value::Nil => "Nil",
+        Value::Number(_) => "Number",
+        Value::String(_) => "String",
+        Value::Function(_) => "Function",
+        Value::Object(_) => "Object",
+    };
+    format!(
+        "\"index\": {}, \"type\": \"{}\", \"value\": {}",
+        index,
+        type_name,
+        json_string(&value.to_string())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_functions() {
+        let source = b"fun f() { var x; fun g() { return x; } return g; } class A { m() {} }";
+        let config = VmConfig::default();
+        let mut heap = Heap::new(config.gc.clone());
+        let script = Compiler::new(Scanner::new(source), &mut heap, &config)
+            .compile()
+            .unwrap();
+        let functions = functions(&script);
+        let names = functions
+            .iter()
+            .map(|function| function.name.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["<script>", "f", "g", "m"]);
+
+        // `OP_CLOSURE` for `g`, capturing `x`
+        let mut json = vec![];
+        assert!(disassemble(source, &config, true, &mut json).unwrap());
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains(r#""opcode": "OP_CLOSURE", "operands": [{"kind": "constant", "index": 0, "type": "Function", "value": "<fn g>"}, {"kind": "upvalue", "isLocal": true, "index": 1}]"#));
+    }
+}
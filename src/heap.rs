@@ -1,21 +1,36 @@
 use std::{
+    borrow::Borrow,
+    hash::{Hash, Hasher},
     ops::{Deref, DerefMut},
     pin::Pin,
     ptr::NonNull,
+    time::{Duration, Instant},
 };
 
 use derivative::Derivative;
 use log::debug;
+use rustc_hash::FxHashMap as HashMap;
 use slotmap::{new_key_type, HopSlotMap as SlotMap, Key};
 use std::fmt::{Debug, Display};
 
-use crate::value::{Function, Upvalue, Value};
+use crate::{
+    config::GcConfig,
+    value::{Class, Function, MethodKind, Object, Upvalue, Value},
+};
+
+pub trait ArenaValue: Debug + Display + PartialEq {
+    /// Bytes used by the item, including what it owns outside the arena
+    fn size(&self) -> usize;
+}
 
-pub trait ArenaValue: Debug + Display + PartialEq {}
-impl<T> ArenaValue for T where T: Debug + Display + PartialEq {}
+impl ArenaValue for String {
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.capacity()
+    }
+}
 
 new_key_type! {
-    pub struct ValueKey;
+    pub struct ObjectKey;
     pub struct FunctionKey;
     pub struct StringKey;
 }
@@ -48,8 +63,38 @@ impl<K: Key, T: ArenaValue> ArenaId<K, T> {
     pub fn marked(&self, black_value: bool) -> bool {
         unsafe { self.arena.as_ref().is_marked(self.id, black_value) }
     }
+
+    pub fn key(&self) -> K {
+        self.id
+    }
 }
 
+/// Points at the contents of a string in the arena, so the intern table doesn't need its own
+/// copy. The buffer doesn't move when the arena does, and strings are never changed after
+/// they're added; the entry is removed in `sweep` before the string is freed.
+#[derive(Clone, Copy, Debug)]
+struct InternedStr(NonNull<str>);
+
+impl Borrow<str> for InternedStr {
+    fn borrow(&self) -> &str {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl Hash for InternedStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Borrow::<str>::borrow(self).hash(state);
+    }
+}
+
+impl PartialEq for InternedStr {
+    fn eq(&self, other: &Self) -> bool {
+        Borrow::<str>::borrow(self) == Borrow::<str>::borrow(other)
+    }
+}
+
+impl Eq for InternedStr {}
+
 #[derive(Clone, Debug, PartialEq)]
 struct Item<T> {
     marked: bool,
@@ -62,7 +107,7 @@ impl<T> Item<T> {
     }
 }
 
-pub type ValueId = ArenaId<ValueKey, Value>;
+pub type ObjectId = ArenaId<ObjectKey, Object>;
 pub type StringId = ArenaId<StringKey, String>;
 pub type FunctionId = ArenaId<FunctionKey, Function>;
 
@@ -90,15 +135,16 @@ impl<K: Key, V: ArenaValue> Arena<K, V> {
     }
 
     fn add(&mut self, value: V, black_value: bool) -> ArenaId<K, V> {
+        let size = value.size();
         let id = self.data.insert(Item::new(value, !black_value));
-        self.bytes_allocated += std::mem::size_of::<V>();
+        self.bytes_allocated += size;
 
         if self.log_gc {
             debug!(
                 "{}/{:?} allocate {} for {}",
                 self.name,
                 id,
-                humansize::format_size(std::mem::size_of::<V>(), humansize::BINARY),
+                humansize::format_size(size, humansize::BINARY),
                 self.data[id].item
             );
         }
@@ -109,21 +155,19 @@ impl<K: Key, V: ArenaValue> Arena<K, V> {
         }
     }
 
-    fn is_marked(&self, index: K, black_value: bool) -> bool {
-        self.data[index].marked == black_value
-    }
-
-    fn flush_gray(&mut self) -> Vec<K> {
-        let capacity = self.gray.capacity();
-        std::mem::replace(&mut self.gray, Vec::with_capacity(capacity))
+    fn id(&mut self, index: K) -> ArenaId<K, V> {
+        ArenaId {
+            id: index,
+            arena: (&mut *self).into(),
+        }
     }
 
-    fn mark(&mut self, index: &ArenaId<K, V>, black_value: bool) -> bool {
-        debug_assert_eq!(index.arena.as_ptr().cast_const(), self);
-        self.mark_raw(index.id, black_value)
+    fn is_marked(&self, index: K, black_value: bool) -> bool {
+        self.data[index].marked == black_value
     }
 
-    fn mark_raw(&mut self, index: K, black_value: bool) -> bool {
+    /// Marks the item black, returning `false` if it already was
+    fn mark(&mut self, index: K, black_value: bool) -> bool {
         let value = &mut self.data[index];
         if value.marked == black_value {
             return false;
@@ -132,24 +176,35 @@ impl<K: Key, V: ArenaValue> Arena<K, V> {
             debug!("{}/{:?} mark {}", self.name, index, value.item);
         }
         value.marked = black_value;
-        self.gray.push(index);
         true
     }
 
     fn sweep(&mut self, black_value: bool) {
+        // Recounted from scratch, which also picks up items that grew or shrank since allocation
+        let mut bytes_allocated = 0;
         self.data.retain(|key, value| {
             let retain = value.marked == black_value;
-            if !retain && self.log_gc {
+            if retain {
+                bytes_allocated += value.item.size();
+            } else if self.log_gc {
                 debug!("{}/{:?} free {}", self.name, key, value.item);
             }
             retain
         });
-        self.bytes_allocated = std::mem::size_of::<V>() * self.data.len();
+        self.bytes_allocated = bytes_allocated;
     }
 
     fn bytes_allocated(&self) -> usize {
         self.bytes_allocated
     }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 }
 
 impl<K: Key, V: ArenaValue> std::ops::Index<&ArenaId<K, V>> for Arena<K, V> {
@@ -184,71 +239,176 @@ impl<K: Key, V: ArenaValue> std::ops::IndexMut<K> for Arena<K, V> {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct BuiltinConstants {
-    pub nil: ValueId,
-    pub true_: ValueId,
-    pub false_: ValueId,
     pub init_string: StringId,
-    pub numbers: Vec<ValueId>,
+    /// Methods that operators call when their left operand is an instance
+    pub add_method: StringId,
+    pub subtract_method: StringId,
+    pub multiply_method: StringId,
+    pub divide_method: StringId,
+    pub less_method: StringId,
+    pub greater_method: StringId,
+    pub equal_method: StringId,
+    pub negate_method: StringId,
+    /// Called by `print` on instances
+    pub to_string_method: StringId,
+    /// The iterator protocol of `for-in` loops
+    pub iter_method: StringId,
+    pub has_next_method: StringId,
+    pub next_method: StringId,
+    /// Classes of the instances natives return, created once so each call doesn't make new ones
+    pub field_class: ObjectId,
+    pub method_class: ObjectId,
+    pub dir_entry_class: ObjectId,
+    pub arg_class: ObjectId,
+    pub gc_stats_class: ObjectId,
 }
 
 impl BuiltinConstants {
     #[must_use]
     pub fn new(heap: &mut Heap) -> Self {
         Self {
-            nil: heap.add_value(Value::Nil),
-            true_: heap.add_value(Value::Bool(true)),
-            false_: heap.add_value(Value::Bool(false)),
             init_string: heap.add_string("init".to_string()),
-            numbers: (0..1024)
-                .map(|n| heap.add_value(Value::Number(n.into())))
-                .collect(),
+            add_method: heap.add_string("__add__".to_string()),
+            subtract_method: heap.add_string("__sub__".to_string()),
+            multiply_method: heap.add_string("__mul__".to_string()),
+            divide_method: heap.add_string("__div__".to_string()),
+            less_method: heap.add_string("__lt__".to_string()),
+            greater_method: heap.add_string("__gt__".to_string()),
+            equal_method: heap.add_string("__eq__".to_string()),
+            negate_method: heap.add_string("__neg__".to_string()),
+            to_string_method: heap.add_string("toString".to_string()),
+            iter_method: heap.add_string("iter".to_string()),
+            has_next_method: heap.add_string("hasNext".to_string()),
+            next_method: heap.add_string("next".to_string()),
+            field_class: Self::class(heap, "Field"),
+            method_class: Self::class(heap, "Method"),
+            dir_entry_class: Self::class(heap, "DirEntry"),
+            arg_class: Self::class(heap, "Arg"),
+            gc_stats_class: Self::class(heap, "GcStats"),
         }
     }
 
-    pub fn bool(&self, val: bool) -> ValueId {
-        if val {
-            self.true_
-        } else {
-            self.false_
-        }
+    fn class(heap: &mut Heap, name: &str) -> ObjectId {
+        let name = heap.add_string(name.to_string());
+        heap.add_object(Class::new(name).into())
+    }
+
+    fn classes(&self) -> [ObjectId; 5] {
+        [
+            self.field_class,
+            self.method_class,
+            self.dir_entry_class,
+            self.arg_class,
+            self.gc_stats_class,
+        ]
+    }
+
+    fn strings(&self) -> [StringId; 13] {
+        [
+            self.init_string,
+            self.add_method,
+            self.subtract_method,
+            self.multiply_method,
+            self.divide_method,
+            self.less_method,
+            self.greater_method,
+            self.equal_method,
+            self.negate_method,
+            self.to_string_method,
+            self.iter_method,
+            self.has_next_method,
+            self.next_method,
+        ]
     }
+}
 
-    pub fn number(&self, n: f64) -> Option<ValueId> {
-        if n.fract() != 0.0 || n.is_nan() || n.is_infinite() {
-            None
-        } else {
-            self.numbers.get(n as usize).copied()
-        }
-    }
+/// Returned by `Heap::stats` (and the `gcStats` native)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Completed collections
+    pub collections: usize,
+    /// Total over all collections
+    pub bytes_freed: usize,
+    pub bytes_allocated: usize,
+    pub live_objects: usize,
+    pub live_strings: usize,
+    pub live_functions: usize,
+}
+
+/// GC pauses of the current cycle, for `--log-gc`
+#[derive(Clone, Debug, Default)]
+struct Pauses {
+    count: usize,
+    total: Duration,
+    longest: Duration,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> Option<Instant> {
+    Some(Instant::now())
 }
 
+// There's no clock on wasm32-unknown-unknown, `Instant::now` panics
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> Option<Instant> {
+    None
+}
+
+/// Collection is incremental: `gc_start` grays the roots, then each `trace_step` blackens a bounded
+/// number of gray items, so marking is spread out between instructions. The tri-color invariant
+/// (no black item points at a white one) is kept by:
+///
+/// * Allocating gray while marking
+/// * `write_barrier` / `global_write_barrier` graying values stored into objects that were already
+///   traced, and into globals
+///
+/// The VM stack isn't covered by barriers, so the VM grays the stack again before the final
+/// `trace` that ends marking, just before `sweep`.
 #[derive(Clone, Debug)]
 pub struct Heap {
     builtin_constants: Option<BuiltinConstants>,
 
     pub strings: Arena<StringKey, String>,
-    pub values: Arena<ValueKey, Value>,
+    pub objects: Arena<ObjectKey, Object>,
     pub functions: Arena<FunctionKey, Function>,
 
+    /// Every live string by its contents, so that `add_string` can return the existing copy.
+    /// Strings are only ever equal to themselves, as in `clox`.
+    interned: HashMap<InternedStr, StringKey>,
+
     log_gc: bool,
+    config: GcConfig,
     next_gc: usize,
     pub black_value: bool,
+    marking: bool,
+    pauses: Pauses,
+    collections: usize,
+    bytes_freed: usize,
 }
 
 impl Heap {
-    pub fn new() -> Pin<Box<Self>> {
-        let log_gc = crate::config::LOG_GC.load();
+    pub fn new(config: GcConfig) -> Pin<Box<Self>> {
+        let log_gc = config.log;
 
         let mut heap = Box::pin(Self {
             builtin_constants: None,
 
             strings: Arena::new("String", log_gc),
-            values: Arena::new("Value", log_gc),
+            objects: Arena::new("Object", log_gc),
             functions: Arena::new("Function", log_gc),
 
+            interned: HashMap::default(),
+
             log_gc,
-            next_gc: 1024 * 1024,
+            next_gc: config
+                .initial_heap_size
+                .min(config.memory_limit.unwrap_or(usize::MAX)),
+            config,
             black_value: true,
+            marking: false,
+            pauses: Pauses::default(),
+            collections: 0,
+            bytes_freed: 0,
         });
 
         // Very important: first pin, *then* initialize the constants, as the `ArenaId`s generated
@@ -262,8 +422,8 @@ impl Heap {
         self.builtin_constants.as_ref().unwrap()
     }
 
-    fn bytes_allocated(&self) -> usize {
-        self.values.bytes_allocated()
+    pub fn bytes_allocated(&self) -> usize {
+        self.objects.bytes_allocated()
             + self.strings.bytes_allocated()
             + self.functions.bytes_allocated()
     }
@@ -272,118 +432,225 @@ impl Heap {
         self.bytes_allocated() > self.next_gc
     }
 
+    /// Whether the heap is past `GcConfig::memory_limit`
+    pub fn over_limit(&self) -> bool {
+        self.config
+            .memory_limit
+            .is_some_and(|limit| self.bytes_allocated() > limit)
+    }
+
+    /// Accounts for an object that grew by `bytes` after it was allocated, like an instance getting a
+    /// new field. Shrinking is only picked up by the next sweep.
+    pub fn object_grew(&mut self, bytes: usize) {
+        self.objects.bytes_allocated += bytes;
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            collections: self.collections,
+            bytes_freed: self.bytes_freed,
+            bytes_allocated: self.bytes_allocated(),
+            live_objects: self.objects.len(),
+            live_strings: self.strings.len(),
+            live_functions: self.functions.len(),
+        }
+    }
+
+    pub fn is_marking(&self) -> bool {
+        self.marking
+    }
+
     pub fn gc_start(&mut self) {
         if self.log_gc {
             debug!("-- gc begin");
         }
 
-        self.values
-            .mark(&self.builtin_constants().nil.clone(), self.black_value);
-        self.values
-            .mark(&self.builtin_constants().true_.clone(), self.black_value);
-        self.values
-            .mark(&self.builtin_constants().false_.clone(), self.black_value);
-        self.strings.mark(
-            &self.builtin_constants().init_string.clone(),
-            self.black_value,
-        );
-        for number in self.builtin_constants().numbers.clone() {
-            self.values.mark(&number, self.black_value);
+        self.marking = true;
+        for string in self.builtin_constants().strings() {
+            self.mark_value(&string.into());
+        }
+        for class in self.builtin_constants().classes() {
+            self.mark_object(&class);
+        }
+    }
+
+    /// Blackens at most `budget` gray items. Returns `true` if there's nothing left to trace.
+    pub fn trace_step(&mut self, mut budget: usize) -> bool {
+        while budget > 0 {
+            if let Some(index) = self.objects.gray.pop() {
+                self.blacken_object(index);
+            } else if let Some(index) = self.functions.gray.pop() {
+                self.blacken_function(index);
+            } else if let Some(index) = self.strings.gray.pop() {
+                self.blacken_string(index);
+            } else {
+                return true;
+            }
+            budget -= 1;
         }
+        self.objects.gray.is_empty()
+            && self.functions.gray.is_empty()
+            && self.strings.gray.is_empty()
     }
 
     pub fn trace(&mut self) {
         if self.log_gc {
             debug!("-- trace start");
         }
-        while !self.functions.gray.is_empty()
-            || !self.strings.gray.is_empty()
-            || !self.values.gray.is_empty()
-        {
-            for index in self.values.flush_gray() {
-                self.blacken_value(index);
-            }
-            for index in self.strings.flush_gray() {
-                self.blacken_string(index);
-            }
-            for index in self.functions.flush_gray() {
-                self.blacken_function(index);
-            }
+        self.trace_step(usize::MAX);
+    }
+
+    /// Marks a root gray
+    pub fn mark_value(&mut self, value: &Value) {
+        self.gray_value(value);
+    }
+
+    pub fn mark_object(&mut self, id: &ObjectId) {
+        self.objects.gray.push(id.id);
+    }
+
+    /// Must be called when `value` is stored into `object`
+    pub fn write_barrier(&mut self, object: &ObjectId, value: &Value) {
+        if self.marking && object.marked(self.black_value) {
+            self.gray_value(value);
+        }
+    }
+
+    /// Must be called when `value` is stored into the global `name`. Globals (both names and
+    /// values) are only traced at the start of a cycle, as if they were a single object that's
+    /// black from then on.
+    pub fn global_write_barrier(&mut self, name: &StringId, value: &Value) {
+        if self.marking {
+            self.strings.gray.push(name.id);
+            self.gray_value(value);
+        }
+    }
+
+    /// Returns the start time of a GC pause if pauses are being logged
+    pub fn pause_start(&self) -> Option<Instant> {
+        if self.log_gc {
+            now()
+        } else {
+            None
         }
     }
 
-    pub fn mark_value(&mut self, id: &ValueId) {
-        self.blacken_value(id.id)
+    pub fn pause_end(&mut self, started: Option<Instant>) {
+        let Some(started) = started else {
+            return;
+        };
+        let pause = started.elapsed();
+        self.pauses.count += 1;
+        self.pauses.total += pause;
+        self.pauses.longest = self.pauses.longest.max(pause);
+        debug!("-- gc pause {:?}", pause);
+        if !self.marking {
+            // That was the end of the cycle
+            let pauses = std::mem::take(&mut self.pauses);
+            debug!(
+                "   {} pauses in cycle, longest {:?}, total {:?}",
+                pauses.count, pauses.longest, pauses.total
+            );
+        }
     }
 
-    pub fn mark_function(&mut self, id: &FunctionId) {
-        self.blacken_function(id.id)
+    fn gray_value(&mut self, value: &Value) {
+        match value {
+            Value::Bool(_) | Value::Nil | Value::Number(_) => {}
+            Value::String(string_id) => self.strings.gray.push(string_id.id),
+            Value::Function(function_id) => self.functions.gray.push(function_id.id),
+            Value::Object(object_id) => self.objects.gray.push(object_id.id),
+        }
     }
 
-    fn blacken_value(&mut self, index: ValueKey) {
+    fn blacken_object(&mut self, index: ObjectKey) {
         if self.log_gc {
-            debug!("Value/{:?} blacken {}", index, self.values[index]);
+            debug!("Object/{:?} blacken {}", index, self.objects[index]);
         }
 
-        let item = &mut self.values.data[index];
+        let item = &mut self.objects.data[index];
         if item.marked == self.black_value {
             return;
         }
         if self.log_gc {
-            debug!("Value/{index:?} mark {}", item.item);
+            debug!("Object/{index:?} mark {}", item.item);
         }
         item.marked = self.black_value;
-        self.values.gray.push(index);
         match &item.item {
-            Value::Bool(_)
-            | Value::Nil
-            | Value::Number(_)
-            | Value::NativeFunction(_)
-            | Value::Upvalue(Upvalue::Open(_)) => {}
-            Value::String(string_id) => self.strings.gray.push(string_id.id),
-            Value::Function(function_id) => self.functions.gray.push(function_id.id),
-            Value::Closure(closure) => {
+            Object::NativeFunction(_) | Object::Upvalue(Upvalue::Open(_)) => {}
+            Object::Closure(closure) => {
                 self.functions.gray.push(closure.function.id);
-                self.values
+                self.objects
                     .gray
-                    .append(&mut closure.upvalues.iter().map(|uv| uv.id).collect());
+                    .extend(closure.upvalues.iter().map(|uv| uv.id));
+            }
+            Object::Upvalue(Upvalue::Closed(value)) => {
+                let value = *value;
+                self.gray_value(&value);
+            }
+            Object::Upvalue(Upvalue::Suspended { generator, .. }) => {
+                self.objects.gray.push(generator.id);
             }
-            Value::Upvalue(Upvalue::Closed(value_id)) => self.values.gray.push(value_id.id),
-            Value::Class(c) => {
+            Object::Class(c) => {
                 self.strings.gray.push(c.name.id);
-                let method_ids = c
-                    .methods
+                let method_ids = MethodKind::ALL
                     .iter()
+                    .flat_map(|kind| c.methods(*kind).iter())
                     .map(|(n, c)| (n.id, c.id))
                     .collect::<Vec<_>>();
+                let fields = c.fields.values().copied().collect::<Vec<_>>();
+                self.strings
+                    .gray
+                    .extend(c.const_fields.iter().map(|name| name.id));
+                if let Some(superclass) = &c.superclass {
+                    self.objects.gray.push(superclass.id);
+                }
                 for (method_name, closure) in method_ids {
                     self.strings.gray.push(method_name);
-                    self.values.gray.push(closure);
+                    self.objects.gray.push(closure);
+                }
+                for field in &fields {
+                    self.gray_value(field);
                 }
             }
-            Value::Instance(instance) => {
-                let mut fields = instance.fields.values().map(|value| value.id).collect();
-                let class_id = instance.class.id;
-                self.values.gray.append(&mut fields);
-                self.values.gray.push(class_id);
+            Object::Instance(instance) => {
+                let fields = instance.fields.values().copied().collect::<Vec<_>>();
+                self.objects.gray.push(instance.class.id);
+                for field in &fields {
+                    self.gray_value(field);
+                }
             }
-            Value::BoundMethod(bound_method) => {
-                let receiver_id = bound_method.receiver.id;
-                let method_id = bound_method.method.id;
-                self.values.gray.push(receiver_id);
-                self.values.gray.push(method_id);
+            Object::BoundMethod(bound_method) => {
+                let receiver = bound_method.receiver;
+                self.objects.gray.push(bound_method.method.id);
+                self.gray_value(&receiver);
+            }
+            Object::Generator(generator) => {
+                let values = generator
+                    .stack
+                    .iter()
+                    .chain(&generator.next)
+                    .copied()
+                    .collect::<Vec<_>>();
+                self.objects.gray.push(generator.closure.id);
+                self.objects
+                    .gray
+                    .extend(generator.open_upvalues.iter().map(|uv| uv.id));
+                for value in &values {
+                    self.gray_value(value);
+                }
             }
         }
     }
 
-    pub fn blacken_string(&mut self, index: StringKey) {
+    fn blacken_string(&mut self, index: StringKey) {
         if self.log_gc {
             debug!("String/{:?} blacken {}", index, self.strings[index]);
         }
-        self.strings.mark_raw(index, self.black_value);
+        self.strings.mark(index, self.black_value);
     }
 
-    pub fn blacken_function(&mut self, index: FunctionKey) {
+    fn blacken_function(&mut self, index: FunctionKey) {
         if self.log_gc {
             debug!("Function/{:?} blacken {}", index, self.functions[index]);
         }
@@ -395,12 +662,12 @@ impl Heap {
             debug!("Function/{index:?} mark {}", item.item);
         }
         item.marked = self.black_value;
-        self.functions.gray.push(index);
         let function = &item.item;
 
         self.strings.gray.push(function.name.id);
-        for constant in function.chunk.constants() {
-            self.values.gray.push(constant.id);
+        let constants = function.chunk.constants().to_vec();
+        for constant in &constants {
+            self.gray_value(constant);
         }
     }
 
@@ -409,18 +676,29 @@ impl Heap {
             debug!("-- sweep start");
         }
 
+        debug_assert!(self.trace_step(0), "sweep with gray items left");
         let before = self.bytes_allocated();
-        self.values.sweep(self.black_value);
+        self.marking = false;
+        self.objects.sweep(self.black_value);
         self.functions.sweep(self.black_value);
+        let (strings, black_value) = (&self.strings, self.black_value);
+        self.interned
+            .retain(|_, key| strings.is_marked(*key, black_value));
         self.strings.sweep(self.black_value);
         self.black_value = !self.black_value;
-
-        self.next_gc = self.bytes_allocated() * crate::config::GC_HEAP_GROW_FACTOR;
+        // Growth that wasn't reported through `object_grew` shows up in the recount
+        let freed = before.saturating_sub(self.bytes_allocated());
+        self.collections += 1;
+        self.bytes_freed += freed;
+
+        // Never past the memory limit, so `needs_gc` is all the VM has to check between instructions
+        self.next_gc = (self.bytes_allocated() * self.config.heap_grow_factor)
+            .min(self.config.memory_limit.unwrap_or(usize::MAX));
         if self.log_gc {
             debug!("-- gc end");
             debug!(
                 "   collected {} (from {} to {}) next at {}",
-                humansize::format_size(before - self.bytes_allocated(), humansize::BINARY),
+                humansize::format_size(freed, humansize::BINARY),
                 humansize::format_size(before, humansize::BINARY),
                 humansize::format_size(self.bytes_allocated(), humansize::BINARY),
                 humansize::format_size(self.next_gc, humansize::BINARY),
@@ -428,15 +706,41 @@ impl Heap {
         }
     }
 
-    pub fn add_value(&mut self, value: Value) -> ValueId {
-        self.values.add(value, self.black_value)
+    // While marking, new items are allocated gray, so they survive the current cycle
+
+    pub fn add_object(&mut self, value: Object) -> ObjectId {
+        let id = self.objects.add(value, self.black_value);
+        if self.marking {
+            self.objects.gray.push(id.id);
+        }
+        id
     }
 
+    /// Returns the existing string if there's one with the same contents
     pub fn add_string(&mut self, value: String) -> StringId {
-        self.strings.add(value, self.black_value)
+        if let Some(&key) = self.interned.get(value.as_str()) {
+            if self.marking {
+                // It may not have been reached yet, and its new user might not be traced
+                self.strings.gray.push(key);
+            }
+            return self.strings.id(key);
+        }
+        let id = self.strings.add(value, self.black_value);
+        self.interned.insert(
+            InternedStr(NonNull::from(self.strings[id.id].as_str())),
+            id.id,
+        );
+        if self.marking {
+            self.strings.gray.push(id.id);
+        }
+        id
     }
 
     pub fn add_function(&mut self, value: Function) -> FunctionId {
-        self.functions.add(value, self.black_value)
+        let id = self.functions.add(value, self.black_value);
+        if self.marking {
+            self.functions.gray.push(id.id);
+        }
+        id
     }
 }
@@ -0,0 +1,405 @@
+//! `heapDump(path)` and `--heap-dump-on-exit`: everything reachable from the GC roots, as JSON.
+//!
+//! Each item reachable from a root is listed with its type, size, outgoing references, and the
+//! path it was first reached by (breadth-first, so it's one of the shortest). Instances are also
+//! summed up per class name, which is usually the quickest way to see what's growing when
+//! comparing two dumps.
+
+use std::{
+    collections::{hash_map::Entry, VecDeque},
+    io::{self, Write},
+};
+
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::{
+    heap::{ArenaValue, FunctionId, FunctionKey, Heap, ObjectId, ObjectKey, StringId, StringKey},
+    value::{MethodKind, Object, Upvalue, Value},
+};
+
+#[derive(Clone, Copy)]
+enum Node {
+    String(StringId),
+    Function(FunctionId),
+    Object(ObjectId),
+}
+
+/// `ArenaId`s are only `Eq` if the item is, so nodes are looked up by their keys
+#[derive(PartialEq, Eq, Hash)]
+enum NodeKey {
+    String(StringKey),
+    Function(FunctionKey),
+    Object(ObjectKey),
+}
+
+impl Node {
+    fn key(&self) -> NodeKey {
+        match self {
+            Node::String(id) => NodeKey::String(id.key()),
+            Node::Function(id) => NodeKey::Function(id.key()),
+            Node::Object(id) => NodeKey::Object(id.key()),
+        }
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(_) | Value::Nil | Value::Number(_) => None,
+            Value::String(id) => Some(Node::String(*id)),
+            Value::Function(id) => Some(Node::Function(*id)),
+            Value::Object(id) => Some(Node::Object(*id)),
+        }
+    }
+
+    fn id(&self) -> String {
+        match self {
+            Node::String(id) => format!("String/{:?}", id.key()),
+            Node::Function(id) => format!("Function/{:?}", id.key()),
+            Node::Object(id) => format!("Object/{:?}", id.key()),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Node::String(_) => "String",
+            Node::Function(_) => "Function",
+            Node::Object(id) => match &**id {
+                Object::Closure(_) => "Closure",
+                Object::NativeFunction(_) => "NativeFunction",
+                Object::Upvalue(_) => "Upvalue",
+                Object::Class(_) => "Class",
+                Object::Instance(_) => "Instance",
+                Object::BoundMethod(_) => "BoundMethod",
+                Object::Generator(_) => "Generator",
+            },
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Node::String(id) => id.size(),
+            Node::Function(id) => id.size(),
+            Node::Object(id) => id.size(),
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            Node::String(id) => id.to_string(),
+            Node::Function(id) => id.to_string(),
+            Node::Object(id) => id.to_string(),
+        }
+    }
+
+    /// The class name, for instances
+    fn class_name(&self) -> Option<String> {
+        match self {
+            Node::Object(id) => match &**id {
+                Object::Instance(instance) => Some(instance.class.as_class().name.to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The same references `Heap::blacken_object` and friends follow, with a name for each
+    fn references(&self) -> Vec<(String, Value)> {
+        match self {
+            Node::String(_) => vec![],
+            Node::Function(function) => std::iter::once(("name".to_string(), function.name.into()))
+                .chain(
+                    function
+                        .chunk
+                        .constants()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, constant)| (format!("constant {}", i), *constant)),
+                )
+                .collect(),
+            Node::Object(object) => match &**object {
+                Object::NativeFunction(_) | Object::Upvalue(Upvalue::Open(_)) => vec![],
+                Object::Upvalue(Upvalue::Closed(value)) => vec![("value".to_string(), *value)],
+                Object::Upvalue(Upvalue::Suspended { generator, .. }) => {
+                    vec![("generator".to_string(), (*generator).into())]
+                }
+                Object::Closure(closure) => {
+                    std::iter::once(("function".to_string(), closure.function.into()))
+                        .chain(
+                            closure
+                                .upvalues
+                                .iter()
+                                .enumerate()
+                                .map(|(i, upvalue)| (format!("upvalue {}", i), (*upvalue).into())),
+                        )
+                        .collect()
+                }
+                Object::Class(class) => std::iter::once(("name".to_string(), class.name.into()))
+                    .chain(
+                        class
+                            .superclass
+                            .map(|superclass| ("superclass".to_string(), superclass.into())),
+                    )
+                    .chain(MethodKind::ALL.iter().flat_map(|kind| {
+                        let label = match kind {
+                            MethodKind::Instance => "method",
+                            MethodKind::Static => "class method",
+                            MethodKind::Getter => "getter",
+                            MethodKind::Setter => "setter",
+                        };
+                        class.methods(*kind).iter().map(move |(name, method)| {
+                            (format!("{} {}", label, **name), (*method).into())
+                        })
+                    }))
+                    .chain(
+                        class
+                            .fields
+                            .iter()
+                            .map(|(name, value)| (format!("field {}", name), *value)),
+                    )
+                    .collect(),
+                Object::Instance(instance) => {
+                    std::iter::once(("class".to_string(), instance.class.into()))
+                        .chain(
+                            instance
+                                .fields
+                                .iter()
+                                .map(|(name, value)| (format!("field {}", name), *value)),
+                        )
+                        .collect()
+                }
+                Object::BoundMethod(bound_method) => vec![
+                    ("receiver".to_string(), bound_method.receiver),
+                    ("method".to_string(), bound_method.method.into()),
+                ],
+                Object::Generator(generator) => {
+                    std::iter::once(("closure".to_string(), generator.closure.into()))
+                        .chain(
+                            generator
+                                .stack
+                                .iter()
+                                .enumerate()
+                                .map(|(i, value)| (format!("slot {}", i), *value)),
+                        )
+                        .chain(
+                            generator
+                                .open_upvalues
+                                .iter()
+                                .enumerate()
+                                .map(|(i, upvalue)| (format!("upvalue {}", i), (*upvalue).into())),
+                        )
+                        .chain(generator.next.map(|next| ("next".to_string(), next)))
+                        .collect()
+                }
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct Total {
+    count: usize,
+    bytes: usize,
+}
+
+/// Writes everything reachable from `roots` (named root, value) as JSON
+pub fn write(heap: &Heap, roots: &[(String, Value)], out: &mut impl Write) -> io::Result<()> {
+    // How each node was first reached: the node it's referenced from (`None` for roots) and the
+    // name of the reference
+    let mut reached_by: HashMap<NodeKey, (Option<Node>, String)> = HashMap::default();
+    let mut order = vec![];
+    let mut queue = VecDeque::new();
+
+    let init_string = (
+        "init string".to_string(),
+        heap.builtin_constants().init_string.into(),
+    );
+    for (name, value) in std::iter::once(&init_string).chain(roots) {
+        if let Some(node) = Node::from_value(value) {
+            if let Entry::Vacant(entry) = reached_by.entry(node.key()) {
+                entry.insert((None, name.clone()));
+                queue.push_back(node);
+            }
+        }
+    }
+    while let Some(node) = queue.pop_front() {
+        order.push(node);
+        for (name, value) in node.references() {
+            if let Some(target) = Node::from_value(&value) {
+                if let Entry::Vacant(entry) = reached_by.entry(target.key()) {
+                    entry.insert((Some(node), name));
+                    queue.push_back(target);
+                }
+            }
+        }
+    }
+
+    let path = |mut node: Node| {
+        let mut path = vec![];
+        loop {
+            let (parent, name) = &reached_by[&node.key()];
+            path.push(name.as_str());
+            match parent {
+                Some(parent) => node = *parent,
+                None => break,
+            }
+        }
+        path.reverse();
+        path.join(" -> ")
+    };
+
+    let mut types: Vec<(&str, Total)> = vec![];
+    let mut classes: HashMap<String, Total> = HashMap::default();
+    for node in &order {
+        let type_name = node.type_name();
+        let total = match types.iter_mut().find(|(name, _)| *name == type_name) {
+            Some((_, total)) => total,
+            None => {
+                types.push((type_name, Total::default()));
+                &mut types.last_mut().unwrap().1
+            }
+        };
+        total.count += 1;
+        total.bytes += node.size();
+        if let Some(class_name) = node.class_name() {
+            let total = classes.entry(class_name).or_default();
+            total.count += 1;
+            total.bytes += node.size();
+        }
+    }
+    types.sort_by_key(|(_, total)| std::cmp::Reverse(total.bytes));
+    let mut classes = classes.into_iter().collect::<Vec<_>>();
+    classes.sort_by(|(a_name, a), (b_name, b)| b.bytes.cmp(&a.bytes).then(a_name.cmp(b_name)));
+
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"bytesAllocated\": {},", heap.bytes_allocated())?;
+    writeln!(
+        out,
+        "  \"reachable\": {{\"count\": {}, \"bytes\": {}}},",
+        order.len(),
+        order.iter().map(Node::size).sum::<usize>()
+    )?;
+
+    writeln!(out, "  \"roots\": [")?;
+    let roots = std::iter::once(&init_string)
+        .chain(roots)
+        .filter_map(|(name, value)| Node::from_value(value).map(|node| (name, node)))
+        .collect::<Vec<_>>();
+    for (i, (name, node)) in roots.iter().enumerate() {
+        writeln!(
+            out,
+            "    {{\"name\": {}, \"target\": {}}}{}",
+            json_string(name),
+            json_string(&node.id()),
+            comma(i, roots.len())
+        )?;
+    }
+    writeln!(out, "  ],")?;
+
+    writeln!(out, "  \"types\": [")?;
+    for (i, (type_name, total)) in types.iter().enumerate() {
+        writeln!(
+            out,
+            "    {{\"type\": {}, \"count\": {}, \"bytes\": {}}}{}",
+            json_string(type_name),
+            total.count,
+            total.bytes,
+            comma(i, types.len())
+        )?;
+    }
+    writeln!(out, "  ],")?;
+
+    writeln!(out, "  \"classes\": [")?;
+    for (i, (class_name, total)) in classes.iter().enumerate() {
+        writeln!(
+            out,
+            "    {{\"name\": {}, \"instances\": {}, \"bytes\": {}}}{}",
+            json_string(class_name),
+            total.count,
+            total.bytes,
+            comma(i, classes.len())
+        )?;
+    }
+    writeln!(out, "  ],")?;
+
+    writeln!(out, "  \"nodes\": [")?;
+    for (i, node) in order.iter().enumerate() {
+        let references = node
+            .references()
+            .iter()
+            .filter_map(|(name, value)| {
+                Node::from_value(value).map(|target| {
+                    format!(
+                        "{{\"name\": {}, \"target\": {}}}",
+                        json_string(name),
+                        json_string(&target.id())
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let class = node
+            .class_name()
+            .map(|name| format!(", \"class\": {}", json_string(&name)))
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "    {{\"id\": {}, \"type\": {}{}, \"size\": {}, \"value\": {}, \"path\": {}, \"references\": [{}]}}{}",
+            json_string(&node.id()),
+            json_string(node.type_name()),
+            class,
+            node.size(),
+            json_string(&node.description()),
+            json_string(&path(*node)),
+            references,
+            comma(i, order.len())
+        )?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+pub(crate) fn comma(i: usize, len: usize) -> &'static str {
+    if i + 1 < len {
+        ","
+    } else {
+        ""
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{InterpretResult, VM};
+
+    #[test]
+    fn heap_dump() {
+        let mut vm = VM::default();
+        assert_eq!(
+            vm.interpret(b"class A {} class B {} var a = A(); var b = B(); b.other = B();"),
+            InterpretResult::Ok
+        );
+        let mut out = vec![];
+        vm.heap_dump(&mut out).unwrap();
+        let dump = String::from_utf8(out).unwrap();
+
+        assert!(dump.contains(r#"{"name": "A", "instances": 1, "bytes": "#));
+        assert!(dump.contains(r#"{"name": "B", "instances": 2, "bytes": "#));
+        assert!(dump.contains(r#""value": "<B instance>", "path": "global b -> field other""#));
+    }
+}
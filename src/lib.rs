@@ -2,9 +2,14 @@ pub mod bitwise;
 pub mod chunk;
 pub mod compiler;
 pub mod config;
+pub mod disassembler;
 pub mod heap;
+pub mod heap_dump;
 pub mod native_functions;
+pub mod optimizer;
 pub mod scanner;
+pub mod test_runner;
 pub mod types;
 pub mod value;
+pub mod verifier;
 pub mod vm;
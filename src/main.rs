@@ -1,31 +1,46 @@
-use log::{error, info, Level, LevelFilter, Metadata, Record};
-use std::{io::Write, path::PathBuf};
+use log::{debug, error, Level, LevelFilter, Metadata, Record};
+use std::{io::Write, path::PathBuf, sync::atomic::Ordering, time::Duration};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use rustyline::{error::ReadlineError, DefaultEditor};
 
 use vm::InterpretResult;
 
-use crate::vm::VM;
+use crate::{
+    compiler::{Compiler, InputStatus},
+    config::VmConfig,
+    native_functions::{Capabilities, Capability},
+    vm::VM,
+};
 
 mod bitwise;
 mod chunk;
 mod compiler;
 mod config;
+mod disassembler;
 mod heap;
+mod heap_dump;
 mod native_functions;
+mod optimizer;
 mod scanner;
+mod test_runner;
 mod types;
 mod value;
+mod verifier;
 mod vm;
 
 struct Logger;
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    /// Not the logs of dependencies
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
     }
 
     fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
         if record.level() <= Level::Warn {
             eprintln!("{}", record.args());
         } else {
@@ -38,10 +53,17 @@ impl log::Log for Logger {
 static LOGGER: Logger = Logger;
 
 #[derive(Parser, Debug)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     file: Option<PathBuf>,
 
+    /// Arguments for the script, returned by `args()`
+    #[arg(last = true, value_name = "ARGS")]
+    script_args: Vec<String>,
+
     /// Standards mode: compatibility with standard `clox`. Passes the standard `clox` test suite.
     #[arg(long)]
     std: bool,
@@ -52,11 +74,89 @@ struct Args {
     #[arg(long)]
     print_code: bool,
 
+    /// Run the peephole optimizer on the compiled bytecode
+    #[arg(short = 'O', long)]
+    optimize: bool,
+
     #[arg(long)]
     stress_gc: bool,
 
     #[arg(long)]
     log_gc: bool,
+
+    /// Bytes allocated before the first garbage collection
+    #[arg(long, value_name = "BYTES", default_value_t = config::GC_INITIAL_HEAP_SIZE)]
+    gc_initial_heap_size: usize,
+
+    /// After a garbage collection, the next one starts when the heap grows to this multiple of what's left
+    #[arg(long, value_name = "FACTOR", default_value_t = config::GC_HEAP_GROW_FACTOR)]
+    gc_grow_factor: usize,
+
+    /// Fail with "Out of memory." when the heap grows past this many bytes
+    #[arg(long, value_name = "BYTES")]
+    memory_limit: Option<usize>,
+
+    /// Stop scripts after executing this many instructions
+    #[arg(long, value_name = "N")]
+    max_instructions: Option<u64>,
+
+    /// Stop scripts after running for this many milliseconds
+    #[arg(long, value_name = "MS")]
+    timeout: Option<u64>,
+
+    /// Only define the natives allowed by these capabilities (pure, time, filesystem, env,
+    /// process); all of them by default
+    #[arg(long, value_name = "CAPABILITY", value_delimiter = ',')]
+    capabilities: Option<Vec<Capability>>,
+
+    /// Same as `--capabilities pure`, like the web build
+    #[arg(long, conflicts_with = "capabilities")]
+    sandbox: bool,
+
+    /// Write everything reachable on the heap to this file as JSON when the interpreter exits
+    #[arg(long, value_name = "PATH")]
+    heap_dump_on_exit: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run test scripts annotated with `// expect: ...` comments, each in a fresh interpreter
+    Test {
+        /// A test script, or a directory to search for `.lox` test scripts
+        path: PathBuf,
+
+        /// Run the tests in standards mode
+        #[arg(long)]
+        std: bool,
+
+        /// Run the tests with GC stress-testing
+        #[arg(long)]
+        stress_gc: bool,
+
+        /// Run the tests with the peephole optimizer enabled
+        #[arg(short = 'O', long)]
+        optimize: bool,
+
+        /// Skip test scripts whose path contains this string; can be given multiple times
+        #[arg(long)]
+        skip: Vec<String>,
+    },
+    /// Compile a script without running it and print the bytecode of every function in it
+    Disasm {
+        file: PathBuf,
+
+        /// Print JSON, with each instruction's operands decoded
+        #[arg(long)]
+        json: bool,
+
+        /// Compile in standards mode
+        #[arg(long)]
+        std: bool,
+
+        /// Show the code after the peephole optimizer
+        #[arg(short = 'O', long)]
+        optimize: bool,
+    },
 }
 
 fn main() {
@@ -66,47 +166,240 @@ fn main() {
 
     let args = Args::parse();
 
-    config::STD_MODE.store(args.std);
-    config::TRACE_EXECUTION.store(args.trace_execution);
-    config::PRINT_CODE.store(args.print_code);
-    config::STRESS_GC.store(args.stress_gc);
-    config::LOG_GC.store(args.log_gc);
+    match args.command {
+        Some(Command::Test {
+            path,
+            std,
+            stress_gc,
+            optimize,
+            skip,
+        }) => run_tests(
+            path,
+            test_runner::TestOptions {
+                std,
+                stress_gc,
+                optimize,
+                skip,
+            },
+        ),
+        Some(Command::Disasm {
+            file,
+            json,
+            std,
+            optimize,
+        }) => {
+            let config = VmConfig {
+                std_mode: std,
+                optimize,
+                ..VmConfig::default()
+            };
+            disassemble(file, &config, json);
+        }
+        None => {}
+    }
+
+    let gc = config::GcConfig {
+        initial_heap_size: args.gc_initial_heap_size,
+        heap_grow_factor: args.gc_grow_factor,
+        memory_limit: args.memory_limit,
+        stress: args.stress_gc,
+        log: args.log_gc,
+    };
+
+    let capabilities = match args.capabilities {
+        _ if args.sandbox => Capabilities::pure(),
+        Some(capabilities) => Capabilities::only(&capabilities),
+        None => Capabilities::all(),
+    };
+
+    let mut vm = VM::new(VmConfig {
+        std_mode: args.std,
+        trace_execution: args.trace_execution,
+        print_code: args.print_code,
+        optimize: args.optimize,
+        gc,
+        capabilities,
+    });
+    vm.set_script_args(args.script_args);
+    vm.set_limits(config::ExecutionLimits {
+        instruction_budget: args.max_instructions,
+        timeout: args.timeout.map(Duration::from_millis),
+    });
+    // Ctrl-C stops the running script rather than the whole interpreter
+    let cancel = vm.cancellation_handle();
+    ctrlc::set_handler(move || cancel.store(true, Ordering::Relaxed))
+        .expect("Failed to set the Ctrl-C handler");
 
     if let Some(path) = args.file {
-        run_file(path);
+        run_file(vm, path, args.heap_dump_on_exit);
     } else {
-        repl();
+        repl(vm, args.heap_dump_on_exit);
     }
 }
 
-fn repl() {
-    let mut vm = VM::new();
+const REPL_HELP: &str = "\
+Enter declarations and statements; the values of expressions are printed. Input continues on the
+next line while it's incomplete, until an empty line.
+
+:help           Show this help
+:globals        List the global variables and their values
+:disasm NAME    Disassemble the function or class in the global NAME";
+
+fn repl(mut vm: VM, heap_dump_on_exit: Option<PathBuf>) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            error!("Failed to start the REPL: {}", e);
+            std::process::exit(74);
+        }
+    };
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".clox_history"));
+    if let Some(history) = &history {
+        // It doesn't exist yet the first time
+        let _ = editor.load_history(history);
+    }
+
+    let mut source = String::new();
     loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
-        let mut line = String::new();
-        if std::io::stdin().read_line(&mut line).unwrap() > 0 {
-            vm.interpret(line.as_bytes());
-        } else {
-            info!("");
-            break;
+        let prompt = if source.is_empty() { "> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                error!("{}", e);
+                break;
+            }
+        };
+        if source.is_empty() {
+            let command = line.trim();
+            if command.starts_with(':') {
+                let _ = editor.add_history_entry(command);
+                repl_command(&vm, command);
+                continue;
+            }
+            if command.is_empty() {
+                continue;
+            }
+        }
+
+        // An empty line submits incomplete input anyway, to get its compile errors
+        let submit = line.trim().is_empty();
+        source.push_str(&line);
+        source.push('\n');
+        if !submit && Compiler::input_status(source.as_bytes()) == InputStatus::Incomplete {
+            // Allow leaving out the semicolon after an expression
+            let terminated = format!("{};", source.trim_end());
+            if Compiler::input_status(terminated.as_bytes()) != InputStatus::Complete {
+                continue;
+            }
+            source = terminated;
+        }
+
+        let _ = editor.add_history_entry(source.trim_end());
+        let result = vm.interpret_line(source.as_bytes());
+        source.clear();
+        if let InterpretResult::Exit(code) = result {
+            save_history(&mut editor, history.as_deref());
+            on_exit(&vm, heap_dump_on_exit);
+            exit(code);
         }
     }
+    save_history(&mut editor, history.as_deref());
+    on_exit(&vm, heap_dump_on_exit);
 }
 
-fn run_file(file: PathBuf) {
+fn repl_command(vm: &VM, command: &str) {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(":help"), None, _) => println!("{}", REPL_HELP),
+        (Some(":globals"), None, _) => {
+            for (name, value) in vm.globals() {
+                println!("{} = {}", name, value);
+            }
+        }
+        (Some(":disasm"), Some(name), None) => match vm.disassemble(name) {
+            Ok(disassembly) => print!("{}", disassembly),
+            Err(e) => error!("{}", e),
+        },
+        _ => error!("Unknown command '{}'; try :help.", command),
+    }
+}
+
+fn save_history(editor: &mut DefaultEditor, history: Option<&std::path::Path>) {
+    if let Some(history) = history {
+        if let Err(e) = editor.save_history(history) {
+            error!(
+                "Failed to save the REPL history to '{}': {}",
+                history.display(),
+                e
+            );
+        }
+    }
+}
+
+fn run_tests(path: PathBuf, options: test_runner::TestOptions) -> ! {
+    let interpreter = std::env::current_exe().unwrap_or_else(|e| {
+        error!("Failed to locate the interpreter executable: {}", e);
+        std::process::exit(74);
+    });
+    let runner = test_runner::TestRunner::new(interpreter, options);
+    std::process::exit(if runner.run(&path) { 0 } else { 1 });
+}
+
+fn disassemble(file: PathBuf, config: &VmConfig, json: bool) -> ! {
+    let source = std::fs::read(file).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(74);
+    });
+    let mut stdout = std::io::stdout().lock();
+    match disassembler::disassemble(&source, config, json, &mut stdout) {
+        Ok(true) => exit(0),
+        Ok(false) => exit(65),
+        Err(e) => {
+            error!("{}", e);
+            exit(74);
+        }
+    }
+}
+
+fn run_file(mut vm: VM, file: PathBuf, heap_dump_on_exit: Option<PathBuf>) {
     match std::fs::read(file) {
         Err(e) => {
             error!("{}", e);
             std::process::exit(74);
         }
         Ok(contents) => {
-            let mut vm = VM::new();
-            match vm.interpret(&contents) {
-                InterpretResult::CompileError => std::process::exit(65),
-                InterpretResult::RuntimeError => std::process::exit(70),
+            let result = vm.interpret(&contents);
+            on_exit(&vm, heap_dump_on_exit);
+            match result {
+                InterpretResult::CompileError => exit(65),
+                InterpretResult::RuntimeError | InterpretResult::LimitExceeded(_) => exit(70),
+                InterpretResult::Exit(code) => exit(code),
                 InterpretResult::Ok => {}
             }
         }
     }
 }
+
+/// `process::exit` skips destructors, so standard output is flushed first
+fn exit(code: i32) -> ! {
+    let _ = std::io::stdout().flush();
+    std::process::exit(code)
+}
+
+fn on_exit(vm: &VM, heap_dump_on_exit: Option<PathBuf>) {
+    if vm.config().gc.log {
+        debug!("-- gc stats {:?}", vm.gc_stats());
+    }
+    if let Some(path) = heap_dump_on_exit {
+        let result = std::fs::File::create(&path)
+            .and_then(|file| vm.heap_dump(&mut std::io::BufWriter::new(file)));
+        if let Err(e) = result {
+            error!("Failed to write heap dump to '{}': {}", path.display(), e);
+        }
+    }
+}
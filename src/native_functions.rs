@@ -1,133 +1,1158 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHasher;
 
 use crate::{
     compiler::Compiler,
-    heap::{Heap, StringId, ValueId},
-    value::Value,
-    vm::VM,
+    heap::{Heap, ObjectId, StringId},
+    heap_dump,
+    value::{Instance, MethodKind, NativeFunctionImpl, Object, Value},
+    vm::{NativeContext, VM},
 };
 
-fn clock_native(heap: &mut Heap, _args: &[&ValueId]) -> Result<ValueId, String> {
-    Ok(heap.add_value(Value::Number(
+fn clock_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    _args: &[Value],
+) -> Result<Value, String> {
+    Ok(Value::Number(
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs_f64(),
-    )))
+    ))
 }
 
-fn sqrt_native(heap: &mut Heap, args: &[&ValueId]) -> Result<ValueId, String> {
-    match &heap.values[args[0]] {
-        Value::Number(n) => Ok(heap.add_value(n.sqrt().into())),
-        x => Err(format!("'sqrt' expected numeric argument, got: {}", *x)),
+fn sqrt_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match &args[0] {
+        Value::Number(n) => Ok(n.sqrt().into()),
+        x => Err(format!("'sqrt' expected numeric argument, got: {}", x)),
     }
 }
 
-fn getattr_native(heap: &mut Heap, args: &[&ValueId]) -> Result<ValueId, String> {
-    match (&heap.values[args[0]], &heap.values[args[1]]) {
-        (Value::Instance(instance), Value::String(string_id)) => Ok(instance
+fn getattr_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match (args[0].object(), &args[1]) {
+        (Some(Object::Instance(instance)), Value::String(string_id)) => Ok(instance
             .fields
-            .get(&heap.strings[string_id])
-            .cloned()
-            .unwrap_or(heap.builtin_constants().nil)),
-        (instance @ Value::Instance(_), x) => Err(format!(
+            .get(&**string_id)
+            .copied()
+            .unwrap_or(Value::Nil)),
+        (Some(instance @ Object::Instance(_)), x) => Err(format!(
             "`getattr` can only index with string indexes, got: `{}` (instance: `{}`)",
             x, instance
         )),
-        (not_instance, _) => Err(format!(
+        _ => Err(format!(
             "`getattr` only works on instances, got `{}`",
-            not_instance
+            args[0]
         )),
     }
 }
 
-fn hasattr_native(heap: &mut Heap, args: &[&ValueId]) -> Result<ValueId, String> {
-    match (&heap.values[args[0]], &heap.values[args[1]]) {
-        (Value::Instance(instance), Value::String(string_id)) => Ok(heap
-            .builtin_constants()
-            .bool(instance.fields.contains_key(&heap.strings[string_id]))),
-        (instance @ Value::Instance(_), x) => Err(format!(
+fn hasattr_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match (args[0].object(), &args[1]) {
+        (Some(Object::Instance(instance)), Value::String(string_id)) => {
+            Ok(instance.fields.contains_key(&**string_id).into())
+        }
+        (Some(instance @ Object::Instance(_)), x) => Err(format!(
             "`hasattr` can only index with string indexes, got: `{}` (instance: `{}`)",
             x, instance
         )),
-        (not_instance, _) => Err(format!(
+        _ => Err(format!(
             "`hasattr` only works on instances, got `{}`",
-            not_instance
+            args[0]
         )),
     }
 }
 
-fn delattr_native(heap: &mut Heap, args: &[&ValueId]) -> Result<ValueId, String> {
-    if let Value::String(string_id) = &heap.values[args[1]] {
+fn delattr_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    if let Value::String(string_id) = &args[1] {
         let field = heap.strings[string_id].clone();
-        if let Value::Instance(instance) = &mut heap.values[args[0]] {
-            instance.fields.remove(&field);
-            Ok(heap.builtin_constants().nil)
-        } else {
-            Err(format!(
-                "`delattr` only works on instances, got `{}`",
-                heap.values[args[0]]
-            ))
+        if let Value::Object(instance_id) = args[0] {
+            if let Object::Instance(instance) = &mut heap.objects[&instance_id] {
+                check_attr_writable("delattr", instance, &args[0], *string_id)?;
+                instance.fields.remove(&field);
+                return Ok(Value::Nil);
+            }
         }
+        Err(format!(
+            "`delattr` only works on instances, got `{}`",
+            args[0]
+        ))
     } else {
         Err(format!(
             "`delattr` can only index with string indexes, got: `{}` (instance: `{}`)",
-            **args[1], **args[0]
+            args[1], args[0]
         ))
     }
 }
 
-fn setattr_native(heap: &mut Heap, args: &[&ValueId]) -> Result<ValueId, String> {
-    if let Value::String(string_id) = &heap.values[args[1]] {
+/// `setattr` and `delattr` can't change frozen instances, or `const` fields even in `init`
+fn check_attr_writable(
+    native: &str,
+    instance: &Instance,
+    value: &Value,
+    field: StringId,
+) -> Result<(), String> {
+    if instance.frozen {
+        Err(format!(
+            "`{}` can't change frozen instance `{}`",
+            native, value
+        ))
+    } else if instance.class.as_class().const_fields.contains(&field) {
+        Err(format!(
+            "`{}` can't change const field '{}'",
+            native, *field
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn setattr_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    if let Value::String(string_id) = &args[1] {
         let field = heap.strings[string_id].clone();
-        if let Value::Instance(instance) = &mut heap.values[args[0]] {
-            instance.fields.insert(field, *args[2]);
-            Ok(heap.builtin_constants().nil)
-        } else {
-            Err(format!(
-                "`setattr` only works on instances, got `{}`",
-                **args[0]
-            ))
+        if let Value::Object(instance_id) = args[0] {
+            if let Object::Instance(instance) = &mut heap.objects[&instance_id] {
+                check_attr_writable("setattr", instance, &args[0], *string_id)?;
+                let size = Instance::field_size(&field);
+                if instance.fields.insert(field, args[2]).is_none() {
+                    heap.object_grew(size);
+                }
+                heap.write_barrier(&instance_id, &args[2]);
+                return Ok(Value::Nil);
+            }
         }
+        Err(format!(
+            "`setattr` only works on instances, got `{}`",
+            args[0]
+        ))
     } else {
         Err(format!(
             "`setattr` can only index with string indexes, got: `{}` (instance: `{}`)",
-            **args[1], **args[0]
+            args[1], args[0]
         ))
     }
 }
 
-pub struct NativeFunctions {
-    string_ids: HashMap<String, StringId>,
+/// Makes the fields of an instance read-only, and returns it
+fn freeze_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match args[0] {
+        Value::Object(mut object) => match &mut *object {
+            Object::Instance(instance) => {
+                instance.frozen = true;
+                Ok(args[0])
+            }
+            _ => Err(format!(
+                "`freeze` only works on instances, got `{}`",
+                args[0]
+            )),
+        },
+        _ => Err(format!(
+            "`freeze` only works on instances, got `{}`",
+            args[0]
+        )),
+    }
 }
 
-impl NativeFunctions {
-    #[must_use]
-    pub fn new() -> Self {
-        Self {
-            string_ids: HashMap::default(),
+fn is_frozen_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    Ok(matches!(args[0].object(), Some(Object::Instance(instance)) if instance.frozen).into())
+}
+
+/// Runs a generator up to its next `yield`, which is what the call returns; or to its end, and
+/// then it returns `nil`. The `VM` does the actual resuming when this returns.
+fn resume_native(
+    _heap: &mut Heap,
+    context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match args[0].object() {
+        Some(Object::Generator(_)) => {
+            context.resume = Some(args[0].as_object());
+            Ok(Value::Nil)
+        }
+        _ => Err(format!(
+            "`resume` only works on generators, got `{}`",
+            args[0]
+        )),
+    }
+}
+
+fn is_done_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match args[0].object() {
+        Some(Object::Generator(generator)) => Ok(generator.is_done().into()),
+        _ => Err(format!(
+            "`isDone` only works on generators, got `{}`",
+            args[0]
+        )),
+    }
+}
+
+/// "nil", "boolean", "number", "string", "function", "class", "trait", "instance" or "generator"
+fn type_of_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let type_name = match &args[0] {
+        Value::Nil => "nil",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Function(_) => "function",
+        Value::Object(object) => match &**object {
+            Object::Closure(_) | Object::NativeFunction(_) | Object::BoundMethod(_) => "function",
+            Object::Class(class) if class.is_trait => "trait",
+            Object::Class(_) => "class",
+            Object::Instance(_) => "instance",
+            Object::Generator(_) => "generator",
+            Object::Upvalue(_) => unreachable!("Upvalues aren't values"),
+        },
+    };
+    Ok(heap.add_string(type_name.to_string()).into())
+}
+
+fn class_of_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match args[0].object() {
+        Some(Object::Instance(instance)) => Ok(instance.class.into()),
+        _ => Err(format!(
+            "`classOf` only works on instances, got `{}`",
+            args[0]
+        )),
+    }
+}
+
+/// `nil` for classes that don't inherit from anything
+fn superclass_of_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match args[0].object() {
+        Some(Object::Class(class)) => Ok(class.superclass.map_or(Value::Nil, Value::from)),
+        _ => Err(format!(
+            "`superclassOf` only works on classes, got `{}`",
+            args[0]
+        )),
+    }
+}
+
+/// Whether the class of `args[0]` is `args[1]` or inherits from it; `false` for non-instances
+fn instance_of_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let Some(Object::Class(_)) = args[1].object() else {
+        return Err(format!(
+            "`instanceOf` expected a class as its second argument, got `{}`",
+            args[1]
+        ));
+    };
+    let target = args[1].as_object();
+    let mut class = match args[0].object() {
+        Some(Object::Instance(instance)) => Some(instance.class),
+        _ => None,
+    };
+    while let Some(current) = class {
+        if current == target {
+            return Ok(true.into());
+        }
+        class = current.as_class().superclass;
+    }
+    Ok(false.into())
+}
+
+/// The fields of an instance, or of a class, sorted by name, as a linked list of `Field`
+/// instances with `name`, `value` and `next` fields; `nil` if there aren't any
+fn fields_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let fields = match args[0].object() {
+        Some(Object::Instance(instance)) => &instance.fields,
+        Some(Object::Class(class)) => &class.fields,
+        _ => {
+            return Err(format!(
+                "`fields` only works on instances and classes, got `{}`",
+                args[0]
+            ))
+        }
+    };
+    let mut fields = fields
+        .iter()
+        .map(|(name, value)| (name.clone(), *value))
+        .collect::<Vec<_>>();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let class = heap.builtin_constants().field_class;
+    Ok(linked_list(heap, class, fields, |heap, (name, value)| {
+        vec![("name", heap.add_string(name).into()), ("value", value)]
+    }))
+}
+
+/// The methods of a class, including inherited ones, sorted by name, as a linked list of `Method`
+/// instances with `name`, `kind` ("method", "class", "getter" or "setter") and `next` fields;
+/// `nil` if there aren't any
+fn methods_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let Some(Object::Class(class)) = args[0].object() else {
+        return Err(format!(
+            "`methods` only works on classes, got `{}`",
+            args[0]
+        ));
+    };
+    let mut methods = MethodKind::ALL
+        .iter()
+        .flat_map(|kind| {
+            let kind_name = match kind {
+                MethodKind::Instance => "method",
+                MethodKind::Static => "class",
+                MethodKind::Getter => "getter",
+                MethodKind::Setter => "setter",
+            };
+            class
+                .methods(*kind)
+                .keys()
+                .map(move |name| (name.to_string(), kind_name))
+        })
+        .collect::<Vec<_>>();
+    methods.sort();
+
+    let class = heap.builtin_constants().method_class;
+    Ok(linked_list(heap, class, methods, |heap, (name, kind)| {
+        vec![
+            ("name", heap.add_string(name).into()),
+            ("kind", heap.add_string(kind.to_string()).into()),
+        ]
+    }))
+}
+
+/// The name of a function, method or class
+fn name_of_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let name = match &args[0] {
+        Value::Function(function) => function.name,
+        Value::Object(object) => match &**object {
+            Object::Closure(closure) => closure.function.name,
+            Object::BoundMethod(bound_method) => bound_method.method.as_closure().function.name,
+            Object::Class(class) => class.name,
+            Object::NativeFunction(native) => heap.add_string(native.name.clone()),
+            _ => return Err(format!("`nameOf` has no name for `{}`", args[0])),
+        },
+        _ => return Err(format!("`nameOf` has no name for `{}`", args[0])),
+    };
+    Ok(name.into())
+}
+
+/// How many arguments a function takes; for a class, how many its initializer takes
+fn arity_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let arity = match &args[0] {
+        Value::Function(function) => function.arity,
+        Value::Object(object) => match &**object {
+            Object::Closure(closure) => closure.function.arity,
+            Object::BoundMethod(bound_method) => bound_method.method.as_closure().function.arity,
+            Object::NativeFunction(native) => usize::from(native.arity),
+            Object::Class(class) => class
+                .methods
+                .get(&heap.builtin_constants().init_string)
+                .map_or(0, |init| init.as_closure().function.arity),
+            _ => {
+                return Err(format!(
+                    "`arity` only works on functions, got `{}`",
+                    args[0]
+                ))
+            }
+        },
+        _ => {
+            return Err(format!(
+                "`arity` only works on functions, got `{}`",
+                args[0]
+            ))
+        }
+    };
+    Ok(Value::Number(arity as f64))
+}
+
+/// Reference identity, unlike `==`: bound methods are only the same as themselves, and numbers are
+/// compared bit for bit, so `same(nan, nan)` but not `same(0, -0)`
+fn same_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let same = match (&args[0], &args[1]) {
+        (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+        (Value::Object(a), Value::Object(b)) => a == b,
+        (a, b) => a == b,
+    };
+    Ok(same.into())
+}
+
+/// A number that's the same for values that are `==`, for using values as keys
+fn hash_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let mut hasher = FxHasher::default();
+    hash_value(&args[0], &mut hasher)?;
+    // Whole numbers up to 2^53 are exact
+    Ok(Value::Number((hasher.finish() >> 11) as f64))
+}
+
+fn hash_value(value: &Value, hasher: &mut FxHasher) -> Result<(), String> {
+    std::mem::discriminant(value).hash(hasher);
+    match value {
+        Value::Bool(b) => b.hash(hasher),
+        Value::Nil => {}
+        Value::Number(n) if n.is_nan() => {
+            return Err("`hash` can't hash NaN, which isn't equal to itself".to_string())
+        }
+        // 0 == -0
+        Value::Number(n) => (n + 0.0).to_bits().hash(hasher),
+        // Strings are interned, so equal strings are the same string
+        Value::String(string) => string.hash(hasher),
+        Value::Function(function) => function.hash(hasher),
+        Value::Object(object) => match &**object {
+            Object::BoundMethod(bound_method) => {
+                hash_value(&bound_method.receiver, hasher)?;
+                bound_method.method.hash(hasher);
+            }
+            _ => object.hash(hasher),
+        },
+    }
+    Ok(())
+}
+
+/// Returns a `GcStats` instance with a field for each of `Heap::stats`
+fn gc_stats_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    _args: &[Value],
+) -> Result<Value, String> {
+    let stats = heap.stats();
+    let mut instance = Instance::new(heap.builtin_constants().gc_stats_class);
+    for (field, value) in [
+        ("collections", stats.collections),
+        ("bytesFreed", stats.bytes_freed),
+        ("bytesAllocated", stats.bytes_allocated),
+        ("objects", stats.live_objects),
+        ("strings", stats.live_strings),
+        ("functions", stats.live_functions),
+    ] {
+        instance
+            .fields
+            .insert(field.to_string(), Value::Number(value as f64));
+    }
+    Ok(heap.add_object(instance.into()).into())
+}
+
+/// Writes everything reachable from the roots to the file at `path`, see `heap_dump`
+fn heap_dump_native(
+    heap: &mut Heap,
+    context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let path = path_arg("heapDump", &args[0])?;
+    File::create(&path)
+        .and_then(|file| heap_dump::write(heap, &context.roots.named(), &mut BufWriter::new(file)))
+        .map_err(|e| format!("Failed to write heap dump to '{}': {}", path, e))?;
+    Ok(Value::Nil)
+}
+
+/// A line from standard input without its line ending, or `nil` at the end of input
+fn read_line_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    _args: &[Value],
+) -> Result<Value, String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Ok(heap.add_string(line).into())
+        }
+        Err(e) => Err(format!("Failed to read from standard input: {}", e)),
+    }
+}
+
+/// Everything left on standard input
+fn read_all_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    _args: &[Value],
+) -> Result<Value, String> {
+    let mut input = String::new();
+    io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|e| format!("Failed to read from standard input: {}", e))?;
+    Ok(heap.add_string(input).into())
+}
+
+/// Like `print`, without the newline
+fn write_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "{}", args[0])
+        .and_then(|()| stdout.flush())
+        .map_err(|e| format!("Failed to write to standard output: {}", e))?;
+    Ok(Value::Nil)
+}
+
+fn read_file_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let path = path_arg("readFile", &args[0])?;
+    let contents =
+        fs::read_to_string(&path).map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+    Ok(heap.add_string(contents).into())
+}
+
+/// Writes the second argument, formatted like `print` would, replacing the file if it exists
+fn write_file_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let path = path_arg("writeFile", &args[0])?;
+    fs::write(&path, args[1].to_string())
+        .map_err(|e| format!("Failed to write '{}': {}", path, e))?;
+    Ok(Value::Nil)
+}
+
+/// Like `writeFile`, but adds to the end of the file
+fn append_file_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let path = path_arg("appendFile", &args[0])?;
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .and_then(|mut file| write!(file, "{}", args[1]))
+        .map_err(|e| format!("Failed to append to '{}': {}", path, e))?;
+    Ok(Value::Nil)
+}
+
+fn file_exists_native(
+    _heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let path = path_arg("fileExists", &args[0])?;
+    Ok(Path::new(&path).exists().into())
+}
+
+/// The entries of a directory, sorted by name, as a linked list of `DirEntry` instances with
+/// `name`, `isDir` and `next` fields; `nil` if the directory is empty
+fn list_dir_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let path = path_arg("listDir", &args[0])?;
+    let mut entries = fs::read_dir(&path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok((
+                        entry.file_name().to_string_lossy().into_owned(),
+                        entry.file_type()?.is_dir(),
+                    ))
+                })
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| format!("Failed to list '{}': {}", path, e))?;
+    entries.sort();
+
+    let class = heap.builtin_constants().dir_entry_class;
+    Ok(linked_list(heap, class, entries, |heap, (name, is_dir)| {
+        vec![
+            ("name", heap.add_string(name).into()),
+            ("isDir", is_dir.into()),
+        ]
+    }))
+}
+
+/// The arguments after `--` on the command line, as a linked list of `Arg` instances with `value`
+/// and `next` fields; `nil` if there aren't any
+fn args_native(
+    heap: &mut Heap,
+    context: &mut NativeContext,
+    _args: &[Value],
+) -> Result<Value, String> {
+    let class = heap.builtin_constants().arg_class;
+    Ok(linked_list(
+        heap,
+        class,
+        context.script_args,
+        |heap, arg| vec![("value", heap.add_string(arg.clone()).into())],
+    ))
+}
+
+/// The value of an environment variable, or `nil` if it isn't set
+fn getenv_native(
+    heap: &mut Heap,
+    _context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    let Value::String(name) = &args[0] else {
+        return Err(format!(
+            "`getenv` expected a variable name string, got: `{}`",
+            args[0]
+        ));
+    };
+    Ok(match std::env::var_os(&**name) {
+        Some(value) => heap.add_string(value.to_string_lossy().into_owned()).into(),
+        None => Value::Nil,
+    })
+}
+
+/// Stops the script; the `VM` unwinds and returns `InterpretResult::Exit` with the code
+fn exit_native(
+    _heap: &mut Heap,
+    context: &mut NativeContext,
+    args: &[Value],
+) -> Result<Value, String> {
+    match args[0] {
+        Value::Number(n)
+            if n.fract() == 0.0 && n >= f64::from(i32::MIN) && n <= f64::from(i32::MAX) =>
+        {
+            context.exit_code = Some(n as i32);
+            Ok(Value::Nil)
         }
+        x => Err(format!(
+            "`exit` expected an integer exit code, got: `{}`",
+            x
+        )),
     }
+}
 
-    pub fn create_names(&mut self, heap: &mut Heap) {
-        for name in ["clock", "sqrt", "getattr", "setattr", "hasattr", "delattr"] {
-            let string_id = heap.add_string(name.to_string());
-            self.string_ids.insert(name.to_string(), string_id);
+/// A linked list of `class` instances, one per item in order, each with the fields `fields`
+/// makes from its item and a `next` field; `nil` if there are no items
+fn linked_list<I: IntoIterator>(
+    heap: &mut Heap,
+    class: ObjectId,
+    items: I,
+    mut fields: impl FnMut(&mut Heap, I::Item) -> Vec<(&'static str, Value)>,
+) -> Value
+where
+    I::IntoIter: DoubleEndedIterator,
+{
+    let mut next = Value::Nil;
+    for item in items.into_iter().rev() {
+        let mut instance = Instance::new(class);
+        for (name, value) in fields(heap, item) {
+            instance.fields.insert(name.to_string(), value);
         }
+        instance.fields.insert("next".to_string(), next);
+        next = heap.add_object(instance.into()).into();
+    }
+    next
+}
+
+fn path_arg(native: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(path) => Ok(path.to_string()),
+        x => Err(format!("`{}` expected a path string, got: `{}`", native, x)),
+    }
+}
+
+/// What a native needs to be allowed to do. Natives are only defined in a `VM` that's been
+/// granted their capability; see `Capabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Only computes a result from the arguments and the heap
+    Pure,
+    /// Reads the clock
+    Time,
+    /// Reads or writes files
+    Filesystem,
+    /// Reads the environment
+    Env,
+    /// Uses the interpreter process: its arguments, standard input and output, and exit status
+    Process,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::Pure,
+        Capability::Time,
+        Capability::Filesystem,
+        Capability::Env,
+        Capability::Process,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Capability::Pure => "pure",
+            Capability::Time => "time",
+            Capability::Filesystem => "filesystem",
+            Capability::Env => "env",
+            Capability::Process => "process",
+        })
+    }
+}
+
+impl std::str::FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL
+            .into_iter()
+            .find(|capability| capability.to_string() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown capability '{}', expected one of: {}",
+                    s,
+                    Capability::ALL
+                        .map(|capability| capability.to_string())
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// The set of capabilities granted to a `VM`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    #[must_use]
+    pub fn all() -> Self {
+        Self::only(&Capability::ALL)
+    }
+
+    /// Enough for scripts that can't observe or affect anything outside the `VM`
+    #[must_use]
+    pub fn pure() -> Self {
+        Self::only(&[Capability::Pure])
+    }
+
+    #[must_use]
+    pub fn only(capabilities: &[Capability]) -> Self {
+        Self(
+            capabilities
+                .iter()
+                .fold(0, |bits, capability| bits | capability.bit()),
+        )
+    }
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+struct Native {
+    name: &'static str,
+    arity: u8,
+    capability: Capability,
+    /// See `NativeFunction::formats_args`
+    formats_args: bool,
+    fun: NativeFunctionImpl,
+}
+
+/// Every native function, whether or not a `VM` has been granted it
+const NATIVES: &[Native] = &[
+    Native {
+        name: "clock",
+        arity: 0,
+        capability: Capability::Time,
+        formats_args: false,
+        fun: clock_native,
+    },
+    Native {
+        name: "sqrt",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: sqrt_native,
+    },
+    Native {
+        name: "getattr",
+        arity: 2,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: getattr_native,
+    },
+    Native {
+        name: "hasattr",
+        arity: 2,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: hasattr_native,
+    },
+    Native {
+        name: "delattr",
+        arity: 2,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: delattr_native,
+    },
+    Native {
+        name: "setattr",
+        arity: 3,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: setattr_native,
+    },
+    Native {
+        name: "freeze",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: freeze_native,
+    },
+    Native {
+        name: "isFrozen",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: is_frozen_native,
+    },
+    Native {
+        name: "typeOf",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: type_of_native,
+    },
+    Native {
+        name: "classOf",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: class_of_native,
+    },
+    Native {
+        name: "superclassOf",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: superclass_of_native,
+    },
+    Native {
+        name: "instanceOf",
+        arity: 2,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: instance_of_native,
+    },
+    Native {
+        name: "fields",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: fields_native,
+    },
+    Native {
+        name: "methods",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: methods_native,
+    },
+    Native {
+        name: "nameOf",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: name_of_native,
+    },
+    Native {
+        name: "arity",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: arity_native,
+    },
+    Native {
+        name: "same",
+        arity: 2,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: same_native,
+    },
+    Native {
+        name: "hash",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: hash_native,
+    },
+    Native {
+        name: "resume",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: resume_native,
+    },
+    Native {
+        name: "isDone",
+        arity: 1,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: is_done_native,
+    },
+    Native {
+        name: "gcStats",
+        arity: 0,
+        capability: Capability::Pure,
+        formats_args: false,
+        fun: gc_stats_native,
+    },
+    Native {
+        name: "heapDump",
+        arity: 1,
+        capability: Capability::Filesystem,
+        formats_args: false,
+        fun: heap_dump_native,
+    },
+    Native {
+        name: "readLine",
+        arity: 0,
+        capability: Capability::Process,
+        formats_args: false,
+        fun: read_line_native,
+    },
+    Native {
+        name: "readAll",
+        arity: 0,
+        capability: Capability::Process,
+        formats_args: false,
+        fun: read_all_native,
+    },
+    Native {
+        name: "write",
+        arity: 1,
+        capability: Capability::Process,
+        formats_args: true,
+        fun: write_native,
+    },
+    Native {
+        name: "readFile",
+        arity: 1,
+        capability: Capability::Filesystem,
+        formats_args: false,
+        fun: read_file_native,
+    },
+    Native {
+        name: "writeFile",
+        arity: 2,
+        capability: Capability::Filesystem,
+        formats_args: true,
+        fun: write_file_native,
+    },
+    Native {
+        name: "appendFile",
+        arity: 2,
+        capability: Capability::Filesystem,
+        formats_args: true,
+        fun: append_file_native,
+    },
+    Native {
+        name: "fileExists",
+        arity: 1,
+        capability: Capability::Filesystem,
+        formats_args: false,
+        fun: file_exists_native,
+    },
+    Native {
+        name: "listDir",
+        arity: 1,
+        capability: Capability::Filesystem,
+        formats_args: false,
+        fun: list_dir_native,
+    },
+    Native {
+        name: "args",
+        arity: 0,
+        capability: Capability::Process,
+        formats_args: false,
+        fun: args_native,
+    },
+    Native {
+        name: "getenv",
+        arity: 1,
+        capability: Capability::Env,
+        formats_args: false,
+        fun: getenv_native,
+    },
+    Native {
+        name: "exit",
+        arity: 1,
+        capability: Capability::Process,
+        formats_args: false,
+        fun: exit_native,
+    },
+];
+
+/// The capability needed by the native called `name`, if it's one that `capabilities` doesn't
+/// include
+pub fn missing_capability(name: &str, capabilities: Capabilities) -> Option<Capability> {
+    NATIVES
+        .iter()
+        .find(|native| native.name == name)
+        .map(|native| native.capability)
+        .filter(|capability| !capabilities.contains(*capability))
+}
+
+/// The natives granted by a set of `Capabilities`
+pub struct NativeFunctions {
+    capabilities: Capabilities,
+}
+
+impl NativeFunctions {
+    #[must_use]
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self { capabilities }
     }
 
-    pub fn register_names(&mut self, compiler: &mut Compiler) {
-        compiler.inject_strings(&self.string_ids);
+    /// Tells the compiler which natives weren't granted. `defined` tells whether a global of that
+    /// name already exists in the `VM`; if so, the script refers to that instead.
+    pub fn deny_names(&self, compiler: &mut Compiler, defined: impl Fn(&str) -> bool) {
+        compiler.deny_natives(
+            NATIVES
+                .iter()
+                .filter(|native| {
+                    !self.capabilities.contains(native.capability) && !defined(native.name)
+                })
+                .map(|native| (native.name.to_string(), native.capability))
+                .collect(),
+        );
     }
 
     pub fn define_functions(&self, vm: &mut VM) {
-        vm.define_native(self.string_ids["clock"], 0, clock_native);
-        vm.define_native(self.string_ids["sqrt"], 1, sqrt_native);
-        vm.define_native(self.string_ids["getattr"], 2, getattr_native);
-        vm.define_native(self.string_ids["hasattr"], 2, hasattr_native);
-        vm.define_native(self.string_ids["delattr"], 2, delattr_native);
-        vm.define_native(self.string_ids["setattr"], 3, setattr_native);
+        for native in NATIVES {
+            if self.capabilities.contains(native.capability) {
+                vm.define_native(native.name, native.arity, native.formats_args, native.fun);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::vm::{InterpretResult, VM};
+
+    #[test]
+    fn file_natives() {
+        let dir = std::env::temp_dir().join(format!("clox-rs-file-natives-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = format!(
+            r#"
+            var dir = "{}";
+            writeFile(dir + "/a.txt", "hello");
+            appendFile(dir + "/a.txt", " world");
+            if (!fileExists(dir + "/a.txt") or fileExists(dir + "/missing.txt")) nil();
+            writeFile(dir + "/b.txt", readFile(dir + "/a.txt") + "!");
+            var names = "";
+            for (var entry = listDir(dir); entry != nil; entry = entry.next) {{
+                names = names + entry.name + ",";
+            }}
+            writeFile(dir + "/names.txt", names);
+            class Greeting {{
+                toString() {{ return "hi"; }}
+            }}
+            writeFile(dir + "/c.txt", Greeting());
+            appendFile(dir + "/c.txt", Greeting());
+            "#,
+            dir.display()
+        );
+
+        let mut vm = VM::default();
+        assert_eq!(vm.interpret(script.as_bytes()), InterpretResult::Ok);
+        assert_eq!(
+            fs::read_to_string(dir.join("b.txt")).unwrap(),
+            "hello world!"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("names.txt")).unwrap(),
+            "a.txt,b.txt,"
+        );
+        assert_eq!(fs::read_to_string(dir.join("c.txt")).unwrap(), "hihi");
+        assert_eq!(
+            vm.interpret(format!(r#"readFile("{}/missing.txt");"#, dir.display()).as_bytes()),
+            InterpretResult::RuntimeError
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
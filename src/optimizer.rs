@@ -0,0 +1,630 @@
+//! Peephole optimizer, run on each `Chunk` after compilation when running with `-O`.
+//!
+//! The chunk is decoded into a list of instructions, with jump operands replaced by the index of
+//! the instruction they land on. Passes rewrite that list until nothing changes, hot sequences are
+//! fused into superinstructions, then it's encoded back into the chunk, recomputing jump offsets
+//! and the run-length encoded line table.
+//!
+//! An instruction that is the target of a jump is never merged into the instruction before it, so
+//! every pass is safe in the presence of control flow.
+
+use num_enum::TryFromPrimitive;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::{
+    chunk::{Chunk, CodeOffset, InstructionDisassembler, OpCode},
+    heap::Heap,
+    types::Line,
+    value::Value,
+};
+
+#[derive(Debug, Clone)]
+struct Instruction {
+    opcode: OpCode,
+    /// Raw operand bytes; empty for jumps, their operand is derived from `target`
+    operands: Vec<u8>,
+    /// Index of the instruction a `Jump`, `JumpIfFalse`, `JumpIfFalsePop` or `Loop` lands on
+    target: Option<usize>,
+    line: Line,
+    deleted: bool,
+}
+
+impl Instruction {
+    fn new(opcode: OpCode, operands: Vec<u8>, line: Line) -> Self {
+        Self {
+            opcode,
+            operands,
+            target: None,
+            line,
+            deleted: false,
+        }
+    }
+
+    fn is_jump(&self) -> bool {
+        matches!(
+            self.opcode,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfFalsePop | OpCode::Loop
+        )
+    }
+
+    fn len(&self) -> usize {
+        if self.is_jump() {
+            3
+        } else {
+            1 + self.operands.len()
+        }
+    }
+
+    /// Execution never continues to the next instruction
+    fn is_unconditional_exit(&self) -> bool {
+        matches!(self.opcode, OpCode::Return | OpCode::Jump | OpCode::Loop)
+    }
+
+    /// Always leaves a boolean on the top of the stack. Comparisons don't: on instances they call
+    /// `__eq__`, `__lt__` or `__gt__`, which can return anything.
+    fn produces_bool(&self) -> bool {
+        matches!(self.opcode, OpCode::True | OpCode::False | OpCode::Not)
+    }
+}
+
+struct Optimizer<'chunk, 'heap> {
+    chunk: &'chunk mut Chunk,
+    heap: &'heap mut Heap,
+    std_mode: bool,
+    instructions: Vec<Instruction>,
+}
+
+/// Optimizes `chunk` in place. If the optimized code can't be encoded (for example, because a
+/// threaded jump would be too long), the chunk is left unchanged.
+pub fn optimize(chunk: &mut Chunk, heap: &mut Heap, std_mode: bool) {
+    let instructions = decode(chunk);
+    let mut optimizer = Optimizer {
+        chunk,
+        heap,
+        std_mode,
+        instructions,
+    };
+    optimizer.run();
+    if let Some(code) = encode(&optimizer.instructions) {
+        optimizer.chunk.replace_code(code);
+    }
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let code = chunk.code();
+    let disassembler = InstructionDisassembler::new(chunk);
+
+    let mut instructions = Vec::new();
+    let mut jump_target_offsets = Vec::new();
+    let mut index_of_offset = HashMap::default();
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let len = disassembler.instruction_len(offset);
+        let opcode = OpCode::try_from_primitive(code[offset]).unwrap();
+        let line = chunk.get_line(&CodeOffset(offset));
+        index_of_offset.insert(offset, instructions.len());
+
+        let mut instruction =
+            Instruction::new(opcode, code[offset + 1..offset + len].to_vec(), line);
+        if instruction.is_jump() {
+            let jump = (usize::from(code[offset + 1]) << 8) + usize::from(code[offset + 2]);
+            jump_target_offsets.push(if opcode == OpCode::Loop {
+                offset + len - jump
+            } else {
+                offset + len + jump
+            });
+            instruction.operands.clear();
+        }
+        instructions.push(instruction);
+        offset += len;
+    }
+    // Jumps to the very end of the code are valid, even if the compiler never emits them
+    index_of_offset.insert(offset, instructions.len());
+
+    let mut jump_target_offsets = jump_target_offsets.into_iter();
+    for instruction in instructions.iter_mut().filter(|i| i.is_jump()) {
+        let target_offset = jump_target_offsets.next().unwrap();
+        instruction.target = Some(index_of_offset[&target_offset]);
+    }
+
+    instructions
+}
+
+/// Returns `None` if a jump doesn't fit into its 16 bit operand
+fn encode(instructions: &[Instruction]) -> Option<Vec<(u8, Line)>> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += instruction.len();
+    }
+    offsets.push(offset);
+
+    let mut code = Vec::with_capacity(offset);
+    for (index, instruction) in instructions.iter().enumerate() {
+        let line = instruction.line;
+        if let Some(target) = instruction.target {
+            let next = offsets[index] + 3;
+            let target = offsets[target];
+            // A threaded jump may now lead backwards; `Jump` and `Loop` are interchangeable
+            let (opcode, jump) = match instruction.opcode {
+                OpCode::Jump | OpCode::Loop if target < next => (OpCode::Loop, next - target),
+                OpCode::Jump | OpCode::Loop => (OpCode::Jump, target - next),
+                opcode if target >= next => (opcode, target - next),
+                _ => return None,
+            };
+            let jump = u16::try_from(jump).ok()?;
+            code.push((opcode.into(), line));
+            code.push(((jump >> 8) as u8, line));
+            code.push((jump as u8, line));
+        } else {
+            code.push((instruction.opcode.into(), line));
+            code.extend(instruction.operands.iter().map(|byte| (*byte, line)));
+        }
+    }
+
+    Some(code)
+}
+
+impl<'chunk, 'heap> Optimizer<'chunk, 'heap> {
+    fn run(&mut self) {
+        loop {
+            let mut changed = self.fold_constants();
+            changed |= self.thread_jumps();
+            changed |= self.remove_dead_code();
+            self.compact();
+            if !changed {
+                break;
+            }
+        }
+        self.fuse_superinstructions();
+        self.compact();
+    }
+
+    /// Removes deleted instructions, retargeting jumps to the next live instruction
+    fn compact(&mut self) {
+        let mut new_index = Vec::with_capacity(self.instructions.len() + 1);
+        let mut live = 0;
+        for instruction in &self.instructions {
+            new_index.push(live);
+            if !instruction.deleted {
+                live += 1;
+            }
+        }
+        new_index.push(live);
+
+        self.instructions.retain(|instruction| !instruction.deleted);
+        for instruction in &mut self.instructions {
+            if let Some(target) = instruction.target.as_mut() {
+                *target = new_index[*target];
+            }
+        }
+    }
+
+    fn jump_targets(&self) -> Vec<bool> {
+        let mut is_target = vec![false; self.instructions.len() + 1];
+        for instruction in self.instructions.iter().filter(|i| !i.deleted) {
+            if let Some(target) = instruction.target {
+                is_target[target] = true;
+            }
+        }
+        is_target
+    }
+
+    /// The `n` live instructions starting at `start`, as long as none but the first is a jump target
+    fn window(&self, start: usize, n: usize, is_target: &[bool]) -> Option<Vec<usize>> {
+        let mut indexes = Vec::with_capacity(n);
+        for (index, instruction) in self.instructions.iter().enumerate().skip(start) {
+            if instruction.deleted {
+                continue;
+            }
+            if !indexes.is_empty() && is_target[index] {
+                return None;
+            }
+            indexes.push(index);
+            if indexes.len() == n {
+                return Some(indexes);
+            }
+        }
+        None
+    }
+
+    fn constant(&self, instruction: &Instruction) -> Option<Value> {
+        let index = match instruction.opcode {
+            OpCode::Constant => usize::from(instruction.operands[0]),
+            OpCode::ConstantLong => {
+                (usize::from(instruction.operands[0]) << 16)
+                    + (usize::from(instruction.operands[1]) << 8)
+                    + usize::from(instruction.operands[2])
+            }
+            _ => return None,
+        };
+        Some(*self.chunk.get_constant(index))
+    }
+
+    fn constant_instruction(&mut self, value: Value, line: Line) -> Option<Instruction> {
+        let index = *self.chunk.make_constant(value);
+        if let Ok(short) = u8::try_from(index) {
+            Some(Instruction::new(OpCode::Constant, vec![short], line))
+        } else if !self.std_mode && index < (1 << 24) {
+            let (_, a, b, c) = crate::bitwise::get_4_bytes(index);
+            Some(Instruction::new(OpCode::ConstantLong, vec![a, b, c], line))
+        } else {
+            None
+        }
+    }
+
+    fn fold_binary(
+        &mut self,
+        left: Value,
+        right: Value,
+        op: OpCode,
+        line: Line,
+    ) -> Option<Instruction> {
+        let bool_instruction =
+            |b: bool| Instruction::new(if b { OpCode::True } else { OpCode::False }, vec![], line);
+        match (left, right) {
+            (Value::Number(a), Value::Number(b)) => match op {
+                OpCode::Add => self.constant_instruction(Value::Number(a + b), line),
+                OpCode::Subtract => self.constant_instruction(Value::Number(a - b), line),
+                OpCode::Multiply => self.constant_instruction(Value::Number(a * b), line),
+                OpCode::Divide => self.constant_instruction(Value::Number(a / b), line),
+                OpCode::Greater => Some(bool_instruction(a > b)),
+                OpCode::Less => Some(bool_instruction(a < b)),
+                OpCode::Equal => Some(bool_instruction(a == b)),
+                _ => None,
+            },
+            (Value::String(a), Value::String(b)) if op == OpCode::Add => {
+                let string_id = self.heap.add_string(format!("{}{}", *a, *b));
+                self.constant_instruction(Value::String(string_id), line)
+            }
+            _ => None,
+        }
+    }
+
+    /// * `Constant a, Constant b, <binary op>` on two numbers or two strings
+    /// * `Constant n, Negate`
+    /// * `Nil|True|False|Constant, Not`
+    /// * `<bool-producing op>, Not, Not`
+    fn fold_constants(&mut self) -> bool {
+        let mut changed = false;
+        let is_target = self.jump_targets();
+
+        for start in 0..self.instructions.len() {
+            if self.instructions[start].deleted {
+                continue;
+            }
+
+            if let Some([first, second, third]) = self.window(start, 3, &is_target).as_deref() {
+                let (first, second, third) = (*first, *second, *third);
+                let left = self.constant(&self.instructions[first]);
+                let right = self.constant(&self.instructions[second]);
+                let op = self.instructions[third].opcode;
+                let line = self.instructions[third].line;
+
+                if let (Some(left), Some(right)) = (left, right) {
+                    if let Some(folded) = self.fold_binary(left, right, op, line) {
+                        self.instructions[first] = folded;
+                        self.instructions[second].deleted = true;
+                        self.instructions[third].deleted = true;
+                        changed = true;
+                        continue;
+                    }
+                }
+
+                if self.instructions[first].produces_bool()
+                    && self.instructions[second].opcode == OpCode::Not
+                    && self.instructions[third].opcode == OpCode::Not
+                {
+                    self.instructions[second].deleted = true;
+                    self.instructions[third].deleted = true;
+                    changed = true;
+                    continue;
+                }
+            }
+
+            if let Some([first, second]) = self.window(start, 2, &is_target).as_deref() {
+                let (first, second) = (*first, *second);
+                let line = self.instructions[second].line;
+                let folded = match (
+                    self.instructions[first].opcode,
+                    self.instructions[second].opcode,
+                ) {
+                    (OpCode::Constant | OpCode::ConstantLong, OpCode::Negate) => {
+                        match self.constant(&self.instructions[first]).unwrap() {
+                            Value::Number(n) => self.constant_instruction(Value::Number(-n), line),
+                            _ => None,
+                        }
+                    }
+                    (OpCode::True, OpCode::Not) => {
+                        Some(Instruction::new(OpCode::False, vec![], line))
+                    }
+                    (OpCode::False | OpCode::Nil, OpCode::Not) => {
+                        Some(Instruction::new(OpCode::True, vec![], line))
+                    }
+                    (OpCode::Constant | OpCode::ConstantLong, OpCode::Not) => {
+                        let falsey = self
+                            .constant(&self.instructions[first])
+                            .unwrap()
+                            .is_falsey();
+                        let opcode = if falsey { OpCode::True } else { OpCode::False };
+                        Some(Instruction::new(opcode, vec![], line))
+                    }
+                    _ => None,
+                };
+                if let Some(folded) = folded {
+                    self.instructions[first] = folded;
+                    self.instructions[second].deleted = true;
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Jumps landing on another jump go directly to its final destination; jumps to the next
+    /// instruction are removed, and jumps to a `Return` become a `Return`
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+
+        for index in 0..self.instructions.len() {
+            let instruction = &self.instructions[index];
+            if instruction.deleted || !instruction.is_jump() {
+                continue;
+            }
+            let opcode = instruction.opcode;
+            let mut target = instruction.target.unwrap();
+
+            // Bounded, in case of jump cycles (`while (true) {}`)
+            for _ in 0..self.instructions.len() {
+                let Some(next) = self.instructions.get(target) else {
+                    break;
+                };
+                let follow = match (opcode, next.opcode) {
+                    (_, OpCode::Jump | OpCode::Loop) => true,
+                    // The condition is still on the stack, so the second jump is taken too
+                    (OpCode::JumpIfFalse, OpCode::JumpIfFalse) => true,
+                    _ => false,
+                };
+                if !follow || next.target == Some(target) {
+                    break;
+                }
+                target = next.target.unwrap();
+            }
+
+            if opcode == OpCode::JumpIfFalse && target <= index {
+                // `JumpIfFalse` can only jump forwards
+                continue;
+            }
+
+            let next_live = (index + 1..self.instructions.len())
+                .find(|&i| !self.instructions[i].deleted)
+                .unwrap_or(self.instructions.len());
+            let jumps_to_return = self
+                .instructions
+                .get(target)
+                .map(|i| i.opcode == OpCode::Return)
+                .unwrap_or(false);
+            let instruction = &mut self.instructions[index];
+
+            if target == next_live {
+                // `JumpIfFalse` doesn't pop the condition, so this is a no-op either way
+                instruction.deleted = true;
+                changed = true;
+            } else if opcode != OpCode::JumpIfFalse && jumps_to_return {
+                *instruction = Instruction::new(OpCode::Return, vec![], instruction.line);
+                changed = true;
+            } else if Some(target) != instruction.target {
+                instruction.target = Some(target);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Runs once, after all other passes: they don't know about superinstructions
+    ///
+    /// * `GetLocal a, GetLocal b, Add` -> `AddLocals a b`
+    /// * `GetLocal a, Constant b, Less` -> `LessLocalConstant a b`
+    /// * `JumpIfFalse, Pop` -> `JumpIfFalsePop`
+    /// * `Constant, Return` -> `ReturnConstant`
+    fn fuse_superinstructions(&mut self) {
+        let is_target = self.jump_targets();
+
+        for start in 0..self.instructions.len() {
+            if self.instructions[start].deleted {
+                continue;
+            }
+
+            if let Some([first, second, third]) = self.window(start, 3, &is_target).as_deref() {
+                let (first, second, third) = (*first, *second, *third);
+                let fused = match (
+                    self.instructions[first].opcode,
+                    self.instructions[second].opcode,
+                    self.instructions[third].opcode,
+                ) {
+                    (OpCode::GetLocal, OpCode::GetLocal, OpCode::Add) => Some(OpCode::AddLocals),
+                    (OpCode::GetLocal, OpCode::Constant, OpCode::Less) => {
+                        Some(OpCode::LessLocalConstant)
+                    }
+                    _ => None,
+                };
+                if let Some(opcode) = fused {
+                    let operands = vec![
+                        self.instructions[first].operands[0],
+                        self.instructions[second].operands[0],
+                    ];
+                    let line = self.instructions[third].line;
+                    self.instructions[first] = Instruction::new(opcode, operands, line);
+                    self.instructions[second].deleted = true;
+                    self.instructions[third].deleted = true;
+                    continue;
+                }
+            }
+
+            if let Some([first, second]) = self.window(start, 2, &is_target).as_deref() {
+                let (first, second) = (*first, *second);
+                match (
+                    self.instructions[first].opcode,
+                    self.instructions[second].opcode,
+                ) {
+                    (OpCode::JumpIfFalse, OpCode::Pop) => {
+                        self.instructions[first].opcode = OpCode::JumpIfFalsePop;
+                        self.instructions[second].deleted = true;
+                    }
+                    (OpCode::Constant, OpCode::Return) => {
+                        let line = self.instructions[second].line;
+                        let instruction = &mut self.instructions[first];
+                        instruction.opcode = OpCode::ReturnConstant;
+                        instruction.line = line;
+                        self.instructions[second].deleted = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Removes code following `Return`, `Jump` and `Loop` up to the next jump target
+    fn remove_dead_code(&mut self) -> bool {
+        let mut changed = false;
+        let is_target = self.jump_targets();
+        let mut reachable = true;
+
+        for (instruction, is_target) in self.instructions.iter_mut().zip(is_target) {
+            if is_target {
+                reachable = true;
+            }
+            if instruction.deleted {
+                continue;
+            }
+            if !reachable {
+                instruction.deleted = true;
+                changed = true;
+            } else if instruction.is_unconditional_exit() {
+                reachable = false;
+            }
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_with(heap: &mut Heap, code: &[u8], constants: Vec<Value>) -> Chunk {
+        let mut chunk = Chunk::new(heap.add_string("test".to_string()));
+        for value in constants {
+            chunk.make_constant(value);
+        }
+        chunk.replace_code(code.iter().map(|byte| (*byte, Line(1))));
+        chunk
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        let mut heap = Heap::new(Default::default());
+        let mut chunk = chunk_with(
+            &mut heap,
+            &[
+                OpCode::Constant.into(),
+                0,
+                OpCode::Constant.into(),
+                1,
+                OpCode::Add.into(),
+                OpCode::Negate.into(),
+                OpCode::Return.into(),
+            ],
+            vec![Value::Number(1.0), Value::Number(2.0)],
+        );
+        optimize(&mut chunk, &mut heap, false);
+
+        assert_eq!(chunk.code(), &[OpCode::ReturnConstant.into(), 3]);
+        assert_eq!(*chunk.get_constant(3usize), Value::Number(-3.0));
+    }
+
+    #[test]
+    fn thread_jumps_and_remove_dead_code() {
+        let mut heap = Heap::new(Default::default());
+        let mut chunk = chunk_with(
+            &mut heap,
+            &[
+                OpCode::Nil.into(),
+                OpCode::JumpIfFalse.into(),
+                0,
+                4,
+                OpCode::Pop.into(),
+                OpCode::Return.into(),
+                OpCode::Nil.into(),
+                OpCode::Return.into(),
+                OpCode::JumpIfFalse.into(),
+                0,
+                1,
+                OpCode::Pop.into(),
+                OpCode::Nil.into(),
+                OpCode::Return.into(),
+            ],
+            vec![],
+        );
+        optimize(&mut chunk, &mut heap, false);
+
+        // The first jump lands on the second one, and is threaded to its target;
+        // then the unreachable `Nil, Return` and the second jump are removed. Finally the first
+        // jump is fused with the `Pop` after it.
+        assert_eq!(
+            chunk.code(),
+            &[
+                OpCode::Nil.into(),
+                OpCode::JumpIfFalsePop.into(),
+                0,
+                1,
+                OpCode::Return.into(),
+                OpCode::Nil.into(),
+                OpCode::Return.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn fuse_superinstructions() {
+        let mut heap = Heap::new(Default::default());
+        let mut chunk = chunk_with(
+            &mut heap,
+            &[
+                OpCode::GetLocal.into(),
+                1,
+                OpCode::Constant.into(),
+                0,
+                OpCode::Less.into(),
+                OpCode::Pop.into(),
+                OpCode::GetLocal.into(),
+                1,
+                OpCode::GetLocal.into(),
+                2,
+                OpCode::Add.into(),
+                OpCode::Return.into(),
+            ],
+            vec![Value::Number(2.0)],
+        );
+        optimize(&mut chunk, &mut heap, false);
+
+        assert_eq!(
+            chunk.code(),
+            &[
+                OpCode::LessLocalConstant.into(),
+                1,
+                0,
+                OpCode::Pop.into(),
+                OpCode::AddLocals.into(),
+                1,
+                2,
+                OpCode::Return.into(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,461 @@
+//! A native replacement for `craftinginterpreters/tool/bin/test.dart`.
+//!
+//! Test scripts are annotated with the same comments the book's test suite uses:
+//!
+//! * `// expect: <line>`: a line of expected output
+//! * `// expect runtime error: <message>`: the script fails at runtime on this line (exit code 70)
+//! * `// Error <message>`: a compile error on this line (exit code 65)
+//! * `// [line N] Error <message>`: a compile error reported on line `N`
+//! * `// nontest`: the file is not a test, skip it
+//!
+//! Each test script is executed in a fresh interpreter process, so that it always starts with
+//! a fresh `VM`.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::{error, info};
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const NON_TEST: &str = "// nontest";
+
+#[derive(Debug, Default, Clone)]
+pub struct TestOptions {
+    /// Pass `--std` to the interpreter
+    pub std: bool,
+    /// Pass `--stress-gc` to the interpreter
+    pub stress_gc: bool,
+    /// Pass `-O` to the interpreter
+    pub optimize: bool,
+    /// Skip test files whose path contains any of these strings
+    pub skip: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ExpectedOutput {
+    line: usize,
+    output: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ExpectedRuntimeError {
+    line: usize,
+    message: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Expectations {
+    output: Vec<ExpectedOutput>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<ExpectedRuntimeError>,
+}
+
+impl Expectations {
+    /// Returns `None` if the script is marked with `// nontest`
+    fn parse(source: &str) -> Option<Self> {
+        let mut expectations = Self::default();
+
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+
+            if text.contains(NON_TEST) {
+                return None;
+            }
+
+            if let Some(pos) = text.find(EXPECT) {
+                expectations.output.push(ExpectedOutput {
+                    line,
+                    output: text[pos + EXPECT.len()..].to_string(),
+                });
+            } else if let Some(pos) = text.find(EXPECT_RUNTIME_ERROR) {
+                expectations.runtime_error = Some(ExpectedRuntimeError {
+                    line,
+                    message: text[pos + EXPECT_RUNTIME_ERROR.len()..].to_string(),
+                });
+            } else if let Some(error) = Self::parse_compile_error(text, line) {
+                expectations.compile_errors.push(error);
+            }
+        }
+
+        Some(expectations)
+    }
+
+    /// Parses both `// Error ...` and `// [line N] Error ...`; the latter optionally tagged with
+    /// the implementation (`// [c line N] Error ...`). Errors tagged for `java` are ignored.
+    fn parse_compile_error(text: &str, line: usize) -> Option<String> {
+        let comment = &text[text.find("// ")? + 3..];
+
+        if comment.starts_with("Error") {
+            return Some(format!("[line {line}] {comment}"));
+        }
+
+        let rest = comment.strip_prefix('[')?;
+        let rest = rest.strip_prefix("c ").unwrap_or(rest);
+        let rest = rest.strip_prefix("line ")?;
+        let (line, error) = rest.split_once("] ")?;
+        let line: usize = line.parse().ok()?;
+        error
+            .starts_with("Error")
+            .then(|| format!("[line {line}] {error}"))
+    }
+
+    fn exit_code(&self) -> i32 {
+        if self.runtime_error.is_some() {
+            70
+        } else if !self.compile_errors.is_empty() {
+            65
+        } else {
+            0
+        }
+    }
+}
+
+/// Extracts the line number from `[line N] ...`, as printed in compile errors and stack traces
+fn error_line(text: &str) -> Option<usize> {
+    let rest = &text[text.find("[line ")? + 6..];
+    rest[..rest.find(']')?].parse().ok()
+}
+
+struct TestCase {
+    path: PathBuf,
+    expectations: Expectations,
+    failures: Vec<String>,
+}
+
+impl TestCase {
+    fn fail<S: ToString>(&mut self, message: S) {
+        self.failures.push(message.to_string());
+    }
+
+    fn validate(&mut self, exit_code: Option<i32>, stdout: &str, stderr: &str) {
+        let error_lines = stderr.lines().collect::<Vec<_>>();
+
+        if self.expectations.runtime_error.is_some() {
+            self.validate_runtime_error(&error_lines);
+        } else {
+            self.validate_compile_errors(&error_lines);
+        }
+        self.validate_exit_code(exit_code, &error_lines);
+        self.validate_output(stdout);
+    }
+
+    fn validate_runtime_error(&mut self, error_lines: &[&str]) {
+        let expected = self.expectations.runtime_error.as_ref().unwrap();
+        let (expected_line, expected_message) = (expected.line, expected.message.clone());
+
+        if error_lines.len() < 2 {
+            self.fail(format!(
+                "Expected runtime error '{expected_message}' and got none."
+            ));
+            return;
+        }
+
+        if error_lines[0] != expected_message {
+            self.fail(format!(
+                "Expected runtime error '{expected_message}' and got:"
+            ));
+            self.fail(error_lines[0]);
+        }
+
+        match error_lines[1..].iter().find_map(|line| error_line(line)) {
+            None => {
+                self.fail("Expected stack trace and got:");
+                for line in &error_lines[1..] {
+                    self.fail(line);
+                }
+            }
+            Some(line) if line != expected_line => {
+                self.fail(format!(
+                    "Expected runtime error on line {expected_line} but was on line {line}."
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+
+    fn validate_compile_errors(&mut self, error_lines: &[&str]) {
+        let mut found = Vec::new();
+        let mut unexpected_count = 0;
+
+        for line in error_lines {
+            if error_line(line).is_some() && line.contains("Error") {
+                if self.expectations.compile_errors.iter().any(|e| e == line) {
+                    found.push(line.to_string());
+                } else {
+                    if unexpected_count < 10 {
+                        self.fail("Unexpected error:");
+                        self.fail(line);
+                    }
+                    unexpected_count += 1;
+                }
+            } else if !line.is_empty() {
+                if unexpected_count < 10 {
+                    self.fail("Unexpected output on stderr:");
+                    self.fail(line);
+                }
+                unexpected_count += 1;
+            }
+        }
+
+        if unexpected_count > 10 {
+            self.fail(format!("(truncated {} more...)", unexpected_count - 10));
+        }
+
+        let missing = self
+            .expectations
+            .compile_errors
+            .iter()
+            .filter(|expected| !found.contains(expected))
+            .cloned()
+            .collect::<Vec<_>>();
+        for error in missing {
+            self.fail(format!("Missing expected error: {error}"));
+        }
+    }
+
+    fn validate_exit_code(&mut self, exit_code: Option<i32>, error_lines: &[&str]) {
+        let expected = self.expectations.exit_code();
+        if exit_code == Some(expected) {
+            return;
+        }
+
+        if error_lines.len() > 10 {
+            self.fail("(truncated...)");
+        }
+        for line in error_lines.iter().take(10) {
+            self.fail(line);
+        }
+        match exit_code {
+            Some(code) => self.fail(format!(
+                "Expected return code {expected} and got {code}. Stderr:"
+            )),
+            None => self.fail(format!(
+                "Expected return code {expected} but the interpreter was killed by a signal."
+            )),
+        }
+    }
+
+    fn validate_output(&mut self, stdout: &str) {
+        let mut output_lines = stdout.lines().collect::<Vec<_>>();
+        // Remove the trailing empty line
+        if output_lines.last() == Some(&"") {
+            output_lines.pop();
+        }
+
+        let mut failures = Vec::new();
+        for (index, line) in output_lines.iter().enumerate() {
+            match self.expectations.output.get(index) {
+                None => failures.push(format!("Got output '{line}' when none was expected.")),
+                Some(expected) if expected.output != *line => failures.push(format!(
+                    "Expected output '{}' on line {} and got '{line}'.",
+                    expected.output, expected.line
+                )),
+                Some(_) => {}
+            }
+        }
+
+        for expected in self.expectations.output.iter().skip(output_lines.len()) {
+            failures.push(format!(
+                "Missing expected output '{}' on line {}.",
+                expected.output, expected.line
+            ));
+        }
+
+        self.failures.append(&mut failures);
+    }
+}
+
+pub struct TestRunner {
+    interpreter: PathBuf,
+    options: TestOptions,
+
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl TestRunner {
+    #[must_use]
+    pub fn new(interpreter: PathBuf, options: TestOptions) -> Self {
+        Self {
+            interpreter,
+            options,
+            passed: 0,
+            failed: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Runs all `.lox` files under `path` (or `path` itself, if it's a file).
+    /// Returns `true` if all tests passed.
+    pub fn run(mut self, path: &Path) -> bool {
+        let mut paths = Vec::new();
+        if let Err(e) = Self::collect_test_files(path, &mut paths) {
+            error!("Failed to read tests from {}: {}", path.display(), e);
+            return false;
+        }
+        paths.sort();
+
+        for path in paths {
+            self.run_test(path);
+        }
+
+        if self.failed == 0 {
+            info!(
+                "All {} tests passed ({} skipped).",
+                self.passed, self.skipped
+            );
+        } else {
+            info!(
+                "{} tests passed. {} tests failed ({} skipped).",
+                self.passed, self.failed, self.skipped
+            );
+        }
+        self.failed == 0
+    }
+
+    fn collect_test_files(path: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                Self::collect_test_files(&entry?.path(), paths)?;
+            }
+        } else if path.extension().map(|ext| ext == "lox").unwrap_or(false) {
+            paths.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn run_test(&mut self, path: PathBuf) {
+        let path_string = path.to_string_lossy();
+        if self
+            .options
+            .skip
+            .iter()
+            .any(|skip| path_string.contains(skip.as_str()))
+        {
+            self.skipped += 1;
+            return;
+        }
+
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                error!("FAIL {}", path.display());
+                error!("     Failed to read test file: {}", e);
+                self.failed += 1;
+                return;
+            }
+        };
+
+        let Some(expectations) = Expectations::parse(&source) else {
+            self.skipped += 1;
+            return;
+        };
+
+        let mut test = TestCase {
+            path,
+            expectations,
+            failures: Vec::new(),
+        };
+
+        let mut command = Command::new(&self.interpreter);
+        if self.options.std {
+            command.arg("--std");
+        }
+        if self.options.stress_gc {
+            command.arg("--stress-gc");
+        }
+        if self.options.optimize {
+            command.arg("-O");
+        }
+        command.arg(&test.path);
+
+        match command.output() {
+            Ok(output) => test.validate(
+                output.status.code(),
+                &String::from_utf8_lossy(&output.stdout),
+                &String::from_utf8_lossy(&output.stderr),
+            ),
+            Err(e) => test.fail(format!(
+                "Failed to run interpreter {}: {}",
+                self.interpreter.display(),
+                e
+            )),
+        }
+
+        if test.failures.is_empty() {
+            self.passed += 1;
+        } else {
+            error!("FAIL {}", test.path.display());
+            for failure in &test.failures {
+                error!("     {}", failure);
+            }
+            self.failed += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expectations() {
+        let expectations = Expectations::parse(
+            "print 1; // expect: 1\n\
+             x = 4; // Error at '4': Reassignment to local 'const'.\n\
+             // [line 7] Error at end: Expect '}' after block.\n\
+             // [c line 8] Error: Unexpected character.\n\
+             // [java line 9] Error: Unexpected character.\n\
+             foo(); // expect runtime error: Undefined variable 'foo'.\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            expectations.output,
+            vec![ExpectedOutput {
+                line: 1,
+                output: "1".to_string()
+            }]
+        );
+        assert_eq!(
+            expectations.compile_errors,
+            vec![
+                "[line 2] Error at '4': Reassignment to local 'const'.",
+                "[line 7] Error at end: Expect '}' after block.",
+                "[line 8] Error: Unexpected character.",
+            ]
+        );
+        assert_eq!(
+            expectations.runtime_error,
+            Some(ExpectedRuntimeError {
+                line: 6,
+                message: "Undefined variable 'foo'.".to_string()
+            })
+        );
+        assert_eq!(expectations.exit_code(), 70);
+    }
+
+    #[test]
+    fn parse_nontest() {
+        assert_eq!(Expectations::parse("// nontest\nprint 1;"), None);
+    }
+
+    #[test]
+    fn validate_output() {
+        let mut test = TestCase {
+            path: PathBuf::from("test.lox"),
+            expectations: Expectations::parse("print 1; // expect: 1\nprint 2; // expect: 2")
+                .unwrap(),
+            failures: Vec::new(),
+        };
+        test.validate(Some(0), "1\n3\n", "");
+        assert_eq!(
+            test.failures,
+            vec!["Expected output '2' on line 2 and got '3'."]
+        );
+    }
+}
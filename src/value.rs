@@ -1,21 +1,51 @@
 use derivative::Derivative;
-use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::{FxHashMap as HashMap, FxHashSet as HashSet};
 
 use crate::{
     chunk::Chunk,
-    config,
-    heap::{FunctionId, Heap, StringId, ValueId},
+    heap::{ArenaValue, FunctionId, Heap, ObjectId, StringId},
+    vm::NativeContext,
 };
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+/// What lives on the stack, in constant tables, globals and fields. Numbers, booleans and `nil`
+/// are stored inline; anything else is a reference into one of the heap arenas.
+#[derive(Debug, PartialOrd, Clone, Copy)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
 
     String(StringId),
-
     Function(FunctionId),
+    Object(ObjectId),
+}
+
+/// Lox's `==`: objects are only equal to themselves, except for bound methods, which are equal if
+/// they bind the same method to the same receiver
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            // IEEE 754, so NaN != NaN
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Object(a), Value::Object(b)) => {
+                a == b
+                    || matches!(
+                        (&**a, &**b),
+                        (Object::BoundMethod(a), Object::BoundMethod(b)) if a == b
+                    )
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Heap-allocated values that aren't strings or functions
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum Object {
     Closure(Closure),
     NativeFunction(NativeFunction),
 
@@ -24,12 +54,18 @@ pub enum Value {
     Class(Class),
     Instance(Instance),
     BoundMethod(BoundMethod),
+    Generator(Generator),
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub enum Upvalue {
     Open(usize),
-    Closed(ValueId),
+    Closed(Value),
+    /// Points into the saved stack of a suspended generator, which it was open in
+    Suspended {
+        generator: ObjectId,
+        slot: usize,
+    },
 }
 
 impl Upvalue {
@@ -44,14 +80,15 @@ impl Upvalue {
 #[derive(Debug, PartialOrd, Clone)]
 pub struct Closure {
     pub function: FunctionId,
-    pub upvalues: Vec<ValueId>,
+    pub upvalues: Vec<ObjectId>,
     pub upvalue_count: usize,
 }
 
 impl PartialEq for Closure {
-    fn eq(&self, _other: &Self) -> bool {
-        // Two different closures are always considered different, even if they close over exactly the same things
-        false
+    fn eq(&self, other: &Self) -> bool {
+        // Identity: two different closures are always considered different, even if they close
+        // over exactly the same things
+        std::ptr::eq(self, other)
     }
 }
 
@@ -66,18 +103,13 @@ impl Closure {
     }
 }
 
-impl Value {
-    pub fn closure(function: FunctionId) -> Value {
-        let upvalue_count = function.upvalue_count;
-        Value::Closure(Closure {
-            function,
-            upvalues: Vec::with_capacity(upvalue_count),
-            upvalue_count,
-        })
+impl Object {
+    pub fn closure(function: FunctionId) -> Object {
+        Object::Closure(Closure::new(function))
     }
 
-    pub fn bound_method(receiver: ValueId, method: ValueId) -> Value {
-        Value::BoundMethod(BoundMethod { receiver, method })
+    pub fn bound_method(receiver: Value, method: ObjectId) -> Object {
+        Object::BoundMethod(BoundMethod { receiver, method })
     }
 }
 
@@ -105,21 +137,27 @@ impl From<FunctionId> for Value {
     }
 }
 
-impl From<Closure> for Value {
+impl From<ObjectId> for Value {
+    fn from(o: ObjectId) -> Self {
+        Value::Object(o)
+    }
+}
+
+impl From<Closure> for Object {
     fn from(c: Closure) -> Self {
-        Value::Closure(c)
+        Object::Closure(c)
     }
 }
 
-impl From<Class> for Value {
+impl From<Class> for Object {
     fn from(c: Class) -> Self {
-        Value::Class(c)
+        Object::Class(c)
     }
 }
 
-impl From<Instance> for Value {
+impl From<Instance> for Object {
     fn from(i: Instance) -> Self {
-        Value::Instance(i)
+        Object::Instance(i)
     }
 }
 
@@ -131,41 +169,108 @@ impl std::fmt::Display for Value {
             Value::Nil => f.pad("nil"),
             Value::String(s) => f.pad(s),
             Value::Function(function_id) => f.pad(&format!("<fn {}>", *function_id.name)),
-            Value::Closure(closure) => f.pad(&format!("<fn {}>", *closure.function.name)),
-            Value::NativeFunction(fun) => {
-                if config::STD_MODE.load() {
+            Value::Object(object_id) => std::fmt::Display::fmt(&**object_id, f),
+        }
+    }
+}
+
+impl ArenaValue for Object {
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + match self {
+                Object::Closure(closure) => {
+                    closure.upvalues.capacity() * std::mem::size_of::<ObjectId>()
+                }
+                Object::NativeFunction(native) => native.name.capacity(),
+                Object::Class(class) => {
+                    class.method_count() * Class::METHOD_SIZE
+                        + class
+                            .fields
+                            .keys()
+                            .map(|name| Instance::field_size(name))
+                            .sum::<usize>()
+                }
+                Object::Instance(instance) => instance
+                    .fields
+                    .keys()
+                    .map(|name| Instance::field_size(name))
+                    .sum(),
+                Object::Generator(generator) => {
+                    generator.stack.capacity() * std::mem::size_of::<Value>()
+                        + generator.open_upvalues.capacity() * std::mem::size_of::<ObjectId>()
+                }
+                Object::Upvalue(_) | Object::BoundMethod(_) => 0,
+            }
+    }
+}
+
+impl std::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_display(f, false)
+    }
+}
+
+/// A value formatted as `clox` does in standards mode, or as plain `Display` does otherwise
+pub struct ValueDisplay<'value> {
+    value: &'value Value,
+    std_mode: bool,
+}
+
+impl std::fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value {
+            Value::Object(object_id) => object_id.fmt_display(f, self.std_mode),
+            value => std::fmt::Display::fmt(value, f),
+        }
+    }
+}
+
+impl Object {
+    fn fmt_display(&self, f: &mut std::fmt::Formatter<'_>, std_mode: bool) -> std::fmt::Result {
+        match self {
+            Object::Closure(closure) => f.pad(&format!("<fn {}>", *closure.function.name)),
+            Object::NativeFunction(fun) => {
+                if std_mode {
                     f.pad("<native fn>")
                 } else {
                     f.pad(&format!("<native fn {}>", fun.name))
                 }
             }
-            Value::Upvalue(_) => f.pad("upvalue"),
-            Value::Class(c) => {
-                if config::STD_MODE.load() {
+            Object::Upvalue(_) => f.pad("upvalue"),
+            Object::Generator(generator) => f.pad(&format!(
+                "<generator {}>",
+                *generator.closure.as_closure().function.name
+            )),
+            Object::Class(c) => {
+                if std_mode {
                     f.pad(&c.name)
+                } else if c.is_trait {
+                    f.pad(&format!("<trait {}>", *c.name))
                 } else {
                     f.pad(&format!("<class {}>", *c.name))
                 }
             }
-            Value::Instance(instance) => {
-                if config::STD_MODE.load() {
-                    f.pad(&format!("{} instance", *(*instance.class).as_class().name))
+            Object::Instance(instance) => {
+                if std_mode {
+                    f.pad(&format!("{} instance", *instance.class.as_class().name))
                 } else {
-                    f.pad(&format!(
-                        "<{} instance>",
-                        *(*instance.class).as_class().name
-                    ))
+                    f.pad(&format!("<{} instance>", *instance.class.as_class().name))
                 }
             }
-            Value::BoundMethod(method) => {
-                if config::STD_MODE.load() {
+            Object::BoundMethod(method) => {
+                if std_mode {
                     f.pad(&format!("{}", *method.method))
                 } else {
+                    // `class` methods are bound to the class itself
+                    let class = match &*method.receiver.as_object() {
+                        Object::Instance(instance) => instance.class.as_class().name,
+                        receiver => receiver.as_class().name,
+                    };
                     f.pad(&format!(
                         "<bound method {}.{} of {}>",
-                        *method.receiver.as_instance().class.as_class().name,
+                        *class,
                         *method.method.as_closure().function.name,
-                        *method.receiver
+                        method.receiver
                     ))
                 }
             }
@@ -174,62 +279,87 @@ impl std::fmt::Display for Value {
 }
 
 impl Value {
+    /// Plain `Display` is the format outside of standards mode
+    pub fn display(&self, std_mode: bool) -> ValueDisplay<'_> {
+        ValueDisplay {
+            value: self,
+            std_mode,
+        }
+    }
+
     pub fn is_falsey(&self) -> bool {
         matches!(self, Self::Bool(false) | Self::Nil)
     }
 
-    pub fn as_closure(&self) -> &Closure {
+    pub fn as_function(&self) -> &FunctionId {
         match self {
-            Value::Closure(c) => c,
-            _ => unreachable!("Expected Closure, found `{}`", self),
+            Value::Function(f) => f,
+            _ => unreachable!("Expected Function, found `{}`", self),
         }
     }
 
-    pub fn as_function(&self) -> &FunctionId {
+    pub fn as_object(&self) -> ObjectId {
         match self {
-            Value::Function(f) => f,
-            _ => unreachable!("Expected Function, found `{}`", self),
+            Value::Object(o) => *o,
+            _ => unreachable!("Expected Object, found `{}`", self),
+        }
+    }
+
+    /// The object behind this value, if it is one
+    pub fn object(&self) -> Option<&Object> {
+        match self {
+            Value::Object(o) => Some(&**o),
+            _ => None,
+        }
+    }
+}
+
+impl Object {
+    pub fn as_closure(&self) -> &Closure {
+        match self {
+            Object::Closure(c) => c,
+            _ => unreachable!("Expected Closure, found `{}`", self),
         }
     }
 
     pub fn as_class(&self) -> &Class {
         match self {
-            Value::Class(c) => c,
+            Object::Class(c) => c,
             _ => unreachable!("Expected Class, found `{}`", self),
         }
     }
 
     pub fn as_class_mut(&mut self) -> &mut Class {
         match self {
-            Value::Class(c) => c,
+            Object::Class(c) => c,
             _ => unreachable!("Expected Class, found `{}`", self),
         }
     }
 
-    pub fn as_instance(&self) -> &Instance {
+    pub fn as_generator(&self) -> &Generator {
         match self {
-            Value::Instance(i) => i,
-            _ => unreachable!("Expected Instance, found `{}`", self),
+            Object::Generator(g) => g,
+            _ => unreachable!("Expected Generator, found `{}`", self),
         }
     }
 
-    pub fn as_instance_mut(&mut self) -> &mut Instance {
+    pub fn as_generator_mut(&mut self) -> &mut Generator {
         match self {
-            Value::Instance(i) => i,
-            _ => unreachable!("Expected Instance, found `{}`", self),
+            Object::Generator(g) => g,
+            _ => unreachable!("Expected Generator, found `{}`", self),
         }
     }
 
     pub fn upvalue_location(&self) -> &Upvalue {
         match self {
-            Value::Upvalue(v) => v,
+            Object::Upvalue(v) => v,
             _ => unreachable!("Expected upvalue, found `{}`", self),
         }
     }
 
     pub fn upvalue_location_mut(&mut self) -> &mut Upvalue {
         match self {
-            Value::Upvalue(v) => v,
+            Object::Upvalue(v) => v,
             _ => unreachable!("Expected upvalue, found `{}`", self),
         }
     }
@@ -241,6 +371,16 @@ pub struct Function {
     pub chunk: Chunk,
     pub name: StringId,
     pub upvalue_count: usize,
+    /// Computed by the compiler; see `Chunk::max_stack_depth`
+    pub max_stack_depth: usize,
+    /// Declared with `fun*`: calling it makes a `Generator` instead of running it
+    pub is_generator: bool,
+}
+
+impl ArenaValue for Function {
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.chunk.owned_bytes()
+    }
 }
 
 impl std::fmt::Display for Function {
@@ -257,6 +397,8 @@ impl Function {
             name,
             chunk: Chunk::new(name),
             upvalue_count: 0,
+            max_stack_depth: 0,
+            is_generator: false,
         }
     }
 }
@@ -266,6 +408,8 @@ impl Function {
 pub struct NativeFunction {
     pub name: String,
     pub arity: u8,
+    /// Instance arguments with a `toString` method are converted with it first, as for `print`
+    pub formats_args: bool,
 
     #[derivative(
             Debug = "ignore",
@@ -276,57 +420,202 @@ pub struct NativeFunction {
     pub fun: NativeFunctionImpl,
 }
 
-pub type NativeFunctionImpl = fn(&mut Heap, &[&ValueId]) -> Result<ValueId, String>;
+pub type NativeFunctionImpl = fn(&mut Heap, &mut NativeContext, &[Value]) -> Result<Value, String>;
 
 fn always_equals<T>(_: &T, _: &T) -> bool {
     true
 }
 
+/// The tables of a `Class` that `OP_METHOD` and its variants add to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MethodKind {
+    Instance,
+    /// `class` methods, called on the class itself
+    Static,
+    Getter,
+    Setter,
+}
+
+impl MethodKind {
+    pub const ALL: [MethodKind; 4] = [
+        MethodKind::Instance,
+        MethodKind::Static,
+        MethodKind::Getter,
+        MethodKind::Setter,
+    ];
+}
+
 #[derive(Debug, PartialEq, Clone, Derivative)]
 #[derivative(PartialOrd)]
 pub struct Class {
     pub name: StringId,
+    /// Traits can't be instantiated or inherited from, only mixed into classes with `with`
+    pub is_trait: bool,
+    /// Set by `OP_INHERIT`. Methods are copied down, so this is only for telling inherited
+    /// methods from the class's own, and for reflection.
+    #[derivative(PartialOrd = "ignore")]
+    pub superclass: Option<ObjectId>,
+    #[derivative(PartialOrd = "ignore")]
+    pub methods: HashMap<StringId, ObjectId>,
+    #[derivative(PartialOrd = "ignore")]
+    pub static_methods: HashMap<StringId, ObjectId>,
     #[derivative(PartialOrd = "ignore")]
-    pub methods: HashMap<StringId, ValueId>,
+    pub getters: HashMap<StringId, ObjectId>,
+    #[derivative(PartialOrd = "ignore")]
+    pub setters: HashMap<StringId, ObjectId>,
+    /// Class-level fields, from `class var` declarations or set on the class at runtime. Unlike
+    /// methods, subclasses don't get a copy.
+    #[derivative(PartialOrd = "ignore")]
+    pub fields: HashMap<String, Value>,
+    /// Instance fields declared `const` in the class body (or a superclass or trait), which can
+    /// only be set while an `init` runs on the instance
+    #[derivative(PartialOrd = "ignore")]
+    pub const_fields: HashSet<StringId>,
 }
 
 impl Class {
+    /// What adding a method adds to the size of the class
+    pub const METHOD_SIZE: usize = std::mem::size_of::<(StringId, ObjectId)>();
+    /// What declaring a `const` field adds to the size of the class
+    pub const CONST_FIELD_SIZE: usize = std::mem::size_of::<StringId>();
+
     #[must_use]
     pub fn new(name: StringId) -> Self {
         Class {
             name,
+            is_trait: false,
+            superclass: None,
             methods: HashMap::default(),
+            static_methods: HashMap::default(),
+            getters: HashMap::default(),
+            setters: HashMap::default(),
+            fields: HashMap::default(),
+            const_fields: HashSet::default(),
         }
     }
+
+    pub fn methods(&self, kind: MethodKind) -> &HashMap<StringId, ObjectId> {
+        match kind {
+            MethodKind::Instance => &self.methods,
+            MethodKind::Static => &self.static_methods,
+            MethodKind::Getter => &self.getters,
+            MethodKind::Setter => &self.setters,
+        }
+    }
+
+    pub fn methods_mut(&mut self, kind: MethodKind) -> &mut HashMap<StringId, ObjectId> {
+        match kind {
+            MethodKind::Instance => &mut self.methods,
+            MethodKind::Static => &mut self.static_methods,
+            MethodKind::Getter => &mut self.getters,
+            MethodKind::Setter => &mut self.setters,
+        }
+    }
+
+    /// How many methods of all kinds the class has
+    pub fn method_count(&self) -> usize {
+        MethodKind::ALL
+            .iter()
+            .map(|kind| self.methods(*kind).len())
+            .sum()
+    }
 }
 
 #[derive(Derivative)]
 #[derivative(Debug, PartialEq, PartialOrd, Clone)]
 pub struct Instance {
-    pub class: ValueId,
+    pub class: ObjectId,
     #[derivative(PartialOrd = "ignore")]
-    pub fields: HashMap<String, ValueId>,
+    pub fields: HashMap<String, Value>,
+    /// Set by `freeze`: no field can be set or deleted any more
+    pub frozen: bool,
 }
 
 impl Instance {
     #[must_use]
-    pub fn new(class: ValueId) -> Self {
+    pub fn new(class: ObjectId) -> Self {
         Instance {
             class,
             fields: HashMap::default(),
+            frozen: false,
         }
     }
+
+    /// What adding a field called `name` adds to the size of the instance
+    pub fn field_size(name: &str) -> usize {
+        std::mem::size_of::<(String, Value)>() + name.len()
+    }
 }
 
 #[derive(Debug, PartialOrd, Clone)]
 pub struct BoundMethod {
-    pub receiver: ValueId,
-    pub method: ValueId,
+    pub receiver: Value,
+    pub method: ObjectId,
 }
 
 impl PartialEq for BoundMethod {
-    fn eq(&self, _other: &Self) -> bool {
-        // Two different bound methods are always considered different
-        false
+    fn eq(&self, other: &Self) -> bool {
+        // `a.m == a.m`, even though each access creates a new bound method
+        self.receiver == other.receiver && self.method == other.method
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done,
+}
+
+/// What the value a generator yields is for, which decides what the `VM` does with it
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum ResumedBy {
+    /// `resume(generator)` or `OP_ITER_NEXT`: the value is pushed
+    Resume,
+    /// `OP_ITER_HAS_NEXT`: the value is kept in `Generator::next` and `true` is pushed, or
+    /// `false` when the generator returns instead
+    HasNext,
+}
+
+/// Made by calling a `fun*` function. `yield` is only allowed in the body of the generator
+/// function itself, so a suspended generator has exactly one call frame: its `ip` and its part of
+/// the stack, starting with the callee slot, are saved here until it's resumed.
+#[derive(Debug, PartialOrd, Clone)]
+pub struct Generator {
+    pub closure: ObjectId,
+    pub state: GeneratorState,
+    pub resumed_by: ResumedBy,
+    pub ip: usize,
+    pub stack: Vec<Value>,
+    /// The upvalues that were open in the generator's frame when it yielded, highest slot first.
+    /// They're `Upvalue::Suspended` until it's resumed.
+    pub open_upvalues: Vec<ObjectId>,
+    /// A value yielded when `OP_ITER_HAS_NEXT` looked ahead, which the next resume returns
+    pub next: Option<Value>,
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Generator {
+    #[must_use]
+    pub fn new(closure: ObjectId, stack: Vec<Value>) -> Self {
+        Generator {
+            closure,
+            state: GeneratorState::Suspended,
+            resumed_by: ResumedBy::Resume,
+            ip: 0,
+            stack,
+            open_upvalues: vec![],
+            next: None,
+        }
+    }
+
+    /// Nothing left to iterate over; `resume` would fail
+    pub fn is_done(&self) -> bool {
+        self.state == GeneratorState::Done && self.next.is_none()
     }
 }
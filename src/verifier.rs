@@ -0,0 +1,486 @@
+//! Checks compiled bytecode before the `VM` runs it, which trusts it completely.
+//!
+//! The first pass decodes each instruction: opcodes must be known, operands must fit in the chunk,
+//! and constant and upvalue indices must be in range (and constants of the right type). The second
+//! follows every path through the code from the start, like `Chunk::max_stack_depth`, checking
+//! that jumps land on instructions, that the stack depth is the same along every path to an
+//! instruction and never goes below zero, that locals exist, and that no path runs off the end.
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    chunk::{InstructionDisassembler, OpCode, Operand},
+    disassembler,
+    value::{Function, Value},
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyError {
+    /// Name of the function the invalid instruction is in
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum VerifyErrorKind {
+    UnknownOpcode(u8),
+    /// The instruction's operands run past the end of the chunk
+    Truncated,
+    ConstantOutOfRange {
+        index: usize,
+        count: usize,
+    },
+    WrongConstantType {
+        index: usize,
+        expected: &'static str,
+    },
+    UpvalueOutOfRange {
+        index: usize,
+        count: usize,
+    },
+    /// The first byte of an upvalue captured by `OP_CLOSURE` must be 0 or 1
+    InvalidUpvalueFlag(u8),
+    /// The slot is past the top of the stack
+    LocalOutOfRange {
+        slot: usize,
+        depth: usize,
+    },
+    JumpOutOfRange,
+    JumpIntoInstruction {
+        target: usize,
+    },
+    StackUnderflow {
+        depth: usize,
+        needed: usize,
+    },
+    InconsistentStackDepth {
+        expected: usize,
+        found: usize,
+    },
+    /// Execution can continue past the last instruction
+    FallsOffEnd,
+    /// `Function::max_stack_depth` is too small
+    MaxStackDepth {
+        declared: usize,
+        needed: usize,
+    },
+    /// `OP_YIELD` in a function that isn't a generator, which has no generator to suspend
+    YieldOutsideGenerator,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode in '{}' at {:04}: ",
+            self.function, self.offset
+        )?;
+        match &self.kind {
+            VerifyErrorKind::UnknownOpcode(byte) => write!(f, "unknown opcode {}", byte),
+            VerifyErrorKind::Truncated => write!(f, "instruction runs past the end of the chunk"),
+            VerifyErrorKind::ConstantOutOfRange { index, count } => {
+                write!(f, "constant {} out of range ({} constants)", index, count)
+            }
+            VerifyErrorKind::WrongConstantType { index, expected } => {
+                write!(f, "constant {} should be a {}", index, expected)
+            }
+            VerifyErrorKind::UpvalueOutOfRange { index, count } => {
+                write!(f, "upvalue {} out of range ({} upvalues)", index, count)
+            }
+            VerifyErrorKind::InvalidUpvalueFlag(flag) => {
+                write!(f, "upvalue flag {} should be 0 or 1", flag)
+            }
+            VerifyErrorKind::LocalOutOfRange { slot, depth } => {
+                write!(
+                    f,
+                    "local slot {} out of range (stack depth {})",
+                    slot, depth
+                )
+            }
+            VerifyErrorKind::JumpOutOfRange => write!(f, "jump out of the chunk"),
+            VerifyErrorKind::JumpIntoInstruction { target } => {
+                write!(f, "jump to {:04}, inside an instruction", target)
+            }
+            VerifyErrorKind::StackUnderflow { depth, needed } => write!(
+                f,
+                "stack underflow (needs {} values, stack depth {})",
+                needed, depth
+            ),
+            VerifyErrorKind::InconsistentStackDepth { expected, found } => write!(
+                f,
+                "stack depth {} here on one path and {} on another",
+                expected, found
+            ),
+            VerifyErrorKind::FallsOffEnd => write!(f, "execution runs off the end of the chunk"),
+            VerifyErrorKind::MaxStackDepth { declared, needed } => write!(
+                f,
+                "maximum stack depth is {}, but it needs {}",
+                declared, needed
+            ),
+            VerifyErrorKind::YieldOutsideGenerator => write!(f, "yield outside of a generator"),
+        }
+    }
+}
+
+/// Checks `script` and every function nested in it
+pub fn verify(script: &Function) -> Result<(), VerifyError> {
+    disassembler::functions(script)
+        .into_iter()
+        .try_for_each(verify_function)
+}
+
+/// Checks only `function`'s own chunk
+pub fn verify_function(function: &Function) -> Result<(), VerifyError> {
+    let error = |offset, kind| VerifyError {
+        function: function.name.to_string(),
+        offset,
+        kind,
+    };
+    let decoded = decode(function).map_err(|(offset, kind)| error(offset, kind))?;
+    let needed = check_stack(function, &decoded).map_err(|(offset, kind)| error(offset, kind))?;
+    if function.max_stack_depth < needed {
+        return Err(error(
+            0,
+            VerifyErrorKind::MaxStackDepth {
+                declared: function.max_stack_depth,
+                needed,
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// What the first pass found at each offset of the code
+#[derive(Clone, Copy)]
+enum Decoded {
+    /// An operand, or part of one
+    Operand,
+    Instruction,
+    /// A jump, and where it lands
+    Jump(usize),
+}
+
+/// The first pass
+fn decode(function: &Function) -> Result<Vec<Decoded>, (usize, VerifyErrorKind)> {
+    let chunk = &function.chunk;
+    let code = chunk.code();
+    let constants = chunk.constants();
+    let mut disassembler = InstructionDisassembler::new(chunk);
+    let mut decoded = vec![Decoded::Operand; code.len()];
+
+    let constant = |offset, index: usize, expected: Option<&'static str>| {
+        let value = constants.get(index).ok_or((
+            offset,
+            VerifyErrorKind::ConstantOutOfRange {
+                index,
+                count: constants.len(),
+            },
+        ))?;
+        match expected {
+            Some(expected) if type_name(value) != expected => Err((
+                offset,
+                VerifyErrorKind::WrongConstantType { index, expected },
+            )),
+            _ => Ok(()),
+        }
+    };
+
+    while *disassembler.offset < code.len() {
+        let offset = *disassembler.offset;
+        let opcode = OpCode::try_from_primitive(code[offset])
+            .map_err(|_| (offset, VerifyErrorKind::UnknownOpcode(code[offset])))?;
+
+        // Its length depends on the function it creates
+        if matches!(opcode, OpCode::Closure | OpCode::ClosureLong) {
+            let operand_len = if opcode.is_long() { 3 } else { 1 };
+            if offset + operand_len >= code.len() {
+                return Err((offset, VerifyErrorKind::Truncated));
+            }
+            let index = disassembler.closure_constant(offset);
+            constant(offset, index, Some("function"))?;
+        }
+        let next = offset + disassembler.instruction_len(offset);
+        if next > code.len() {
+            return Err((offset, VerifyErrorKind::Truncated));
+        }
+
+        use OpCode::*;
+        decoded[offset] = match opcode {
+            Jump | JumpIfFalse | JumpIfFalsePop | Loop => {
+                let distance = (usize::from(code[offset + 1]) << 8) + usize::from(code[offset + 2]);
+                let target = if opcode == Loop {
+                    next.checked_sub(distance)
+                } else {
+                    Some(next + distance)
+                };
+                match target {
+                    Some(target) if target < code.len() => Decoded::Jump(target),
+                    _ => return Err((offset, VerifyErrorKind::JumpOutOfRange)),
+                }
+            }
+            Yield if !function.is_generator => {
+                return Err((offset, VerifyErrorKind::YieldOutsideGenerator));
+            }
+            _ => {
+                let expected = match opcode {
+                    DefineGlobal
+                    | DefineGlobalLong
+                    | DefineGlobalConst
+                    | DefineGlobalConstLong
+                    | GetGlobal
+                    | GetGlobalLong
+                    | SetGlobal
+                    | SetGlobalLong
+                    | Class
+                    | ClassLong
+                    | Trait
+                    | TraitLong
+                    | GetProperty
+                    | GetPropertyLong
+                    | SetProperty
+                    | SetPropertyLong
+                    | Method
+                    | MethodLong
+                    | StaticMethod
+                    | StaticMethodLong
+                    | Getter
+                    | GetterLong
+                    | Setter
+                    | SetterLong
+                    | ConstField
+                    | ConstFieldLong
+                    | Invoke
+                    | InvokeLong
+                    | GetSuper
+                    | GetSuperLong
+                    | SuperInvoke
+                    | SuperInvokeLong => Some("string"),
+                    Closure | ClosureLong => Some("function"),
+                    _ => None,
+                };
+                for (i, operand) in disassembler.operands().into_iter().enumerate() {
+                    match operand {
+                        Operand::Constant(index) => constant(offset, index, expected)?,
+                        Operand::Byte(index) if matches!(opcode, GetUpvalue | SetUpvalue) => {
+                            check_upvalue(function, offset, index)?;
+                        }
+                        Operand::Upvalue { is_local, index } => {
+                            // After the function constant, two bytes per upvalue
+                            let flag = code[offset + 2 * i + if opcode.is_long() { 2 } else { 0 }];
+                            if flag > 1 {
+                                return Err((offset, VerifyErrorKind::InvalidUpvalueFlag(flag)));
+                            }
+                            if !is_local {
+                                check_upvalue(function, offset, index)?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Decoded::Instruction
+            }
+        };
+        *disassembler.offset = next;
+    }
+
+    Ok(decoded)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Function(_) => "function",
+        _ => "value",
+    }
+}
+
+fn check_upvalue(
+    function: &Function,
+    offset: usize,
+    index: usize,
+) -> Result<(), (usize, VerifyErrorKind)> {
+    if index < function.upvalue_count {
+        Ok(())
+    } else {
+        Err((
+            offset,
+            VerifyErrorKind::UpvalueOutOfRange {
+                index,
+                count: function.upvalue_count,
+            },
+        ))
+    }
+}
+
+/// The second pass. Returns the maximum stack depth.
+fn check_stack(
+    function: &Function,
+    decoded: &[Decoded],
+) -> Result<usize, (usize, VerifyErrorKind)> {
+    use OpCode::*;
+
+    let chunk = &function.chunk;
+    let code = chunk.code();
+    let mut disassembler = InstructionDisassembler::new(chunk);
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    // The callee and its arguments
+    let initial_depth = function.arity + 1;
+    let mut worklist = vec![(0, initial_depth)];
+    let mut max_depth = initial_depth;
+
+    while let Some((offset, depth)) = worklist.pop() {
+        if offset == code.len() {
+            return Err((offset, VerifyErrorKind::FallsOffEnd));
+        }
+        let jump = match decoded[offset] {
+            Decoded::Operand => {
+                return Err((
+                    offset,
+                    VerifyErrorKind::JumpIntoInstruction { target: offset },
+                ))
+            }
+            Decoded::Instruction => None,
+            Decoded::Jump(target) => Some(target),
+        };
+        match depths[offset] {
+            Some(expected) if expected != depth => {
+                return Err((
+                    offset,
+                    VerifyErrorKind::InconsistentStackDepth {
+                        expected,
+                        found: depth,
+                    },
+                ));
+            }
+            Some(_) => continue,
+            None => depths[offset] = Some(depth),
+        }
+
+        let opcode = OpCode::try_from_primitive(code[offset]).unwrap();
+        *disassembler.offset = offset;
+        for operand in disassembler.operands() {
+            let slot = match operand {
+                Operand::Byte(slot)
+                    if matches!(
+                        opcode,
+                        GetLocal | GetLocalLong | SetLocal | SetLocalLong | AddLocals
+                    ) =>
+                {
+                    slot
+                }
+                Operand::Byte(slot) if opcode == LessLocalConstant => slot,
+                // The iterator, and the position after it
+                Operand::Byte(slot)
+                    if matches!(
+                        opcode,
+                        IterHasNext | IterHasNextLong | IterNext | IterNextLong
+                    ) =>
+                {
+                    slot + 1
+                }
+                Operand::Upvalue {
+                    is_local: true,
+                    index,
+                } => index,
+                _ => continue,
+            };
+            if slot >= depth {
+                return Err((offset, VerifyErrorKind::LocalOutOfRange { slot, depth }));
+            }
+        }
+
+        let (pops, pushes) = chunk.stack_effect(offset);
+        // Some instructions look at the top of the stack without popping it
+        let peeks = match opcode {
+            SetGlobal | SetGlobalLong | SetUpvalue | SetLocal | SetLocalLong | JumpIfFalse
+            | JumpIfFalsePop | Negate | Not | GetProperty | GetPropertyLong | Dup | ConstField
+            | ConstFieldLong => 1,
+            _ => 0,
+        };
+        let needed = pops.max(peeks);
+        if depth < needed {
+            return Err((offset, VerifyErrorKind::StackUnderflow { depth, needed }));
+        }
+        max_depth = max_depth.max(depth + chunk.peak_pushes(offset));
+        let depth = depth - pops + pushes;
+        max_depth = max_depth.max(depth);
+
+        let next = offset + disassembler.instruction_len(offset);
+        match (opcode, jump) {
+            (Return | ReturnConstant, _) => {}
+            (Jump | Loop, Some(target)) => worklist.push((target, depth)),
+            (JumpIfFalse, Some(target)) => {
+                worklist.push((target, depth));
+                worklist.push((next, depth));
+            }
+            (JumpIfFalsePop, Some(target)) => {
+                worklist.push((target, depth));
+                worklist.push((next, depth - 1));
+            }
+            _ => worklist.push((next, depth)),
+        }
+    }
+
+    Ok(max_depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::GcConfig, heap::Heap, types::Line};
+
+    fn check(code: &[u8]) -> Result<(), VerifyErrorKind> {
+        let mut heap = Heap::new(GcConfig::default());
+        let mut function = Function::new(0, heap.add_string("f".to_string()));
+        function.chunk.make_constant(Value::Number(1.0));
+        for byte in code {
+            function.chunk.write(*byte, Line(1));
+        }
+        function.max_stack_depth = 8;
+        verify_function(&function).map_err(|e| e.kind)
+    }
+
+    #[test]
+    fn errors() {
+        use OpCode::*;
+        let [constant, jump, jump_if_false, pop, nil, ret, get_local, add] =
+            [Constant, Jump, JumpIfFalse, Pop, Nil, Return, GetLocal, Add].map(u8::from);
+
+        assert_eq!(check(&[nil, ret]), Ok(()));
+        assert_eq!(check(&[250]), Err(VerifyErrorKind::UnknownOpcode(250)));
+        assert_eq!(check(&[nil, constant]), Err(VerifyErrorKind::Truncated));
+        assert_eq!(
+            check(&[constant, 1, ret]),
+            Err(VerifyErrorKind::ConstantOutOfRange { index: 1, count: 1 })
+        );
+        assert_eq!(
+            check(&[jump, 0, 1, constant, 0, ret]),
+            Err(VerifyErrorKind::JumpIntoInstruction { target: 4 })
+        );
+        assert_eq!(
+            check(&[jump, 0, 9, nil, ret]),
+            Err(VerifyErrorKind::JumpOutOfRange)
+        );
+        assert_eq!(
+            check(&[add, ret]),
+            Err(VerifyErrorKind::StackUnderflow {
+                depth: 1,
+                needed: 2
+            })
+        );
+        assert_eq!(
+            check(&[get_local, 1, ret]),
+            Err(VerifyErrorKind::LocalOutOfRange { slot: 1, depth: 1 })
+        );
+        // One more value on the stack at the `Pop` if the jump isn't taken
+        assert_eq!(
+            check(&[nil, jump_if_false, 0, 1, nil, pop, ret]),
+            Err(VerifyErrorKind::InconsistentStackDepth {
+                expected: 3,
+                found: 2
+            })
+        );
+        assert_eq!(check(&[nil, pop]), Err(VerifyErrorKind::FallsOffEnd));
+    }
+}
@@ -1,20 +1,28 @@
 use std::collections::VecDeque;
+use std::io::Write;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use log::{debug, error, info};
 use rustc_hash::FxHashMap as HashMap;
 
 use crate::chunk::InstructionDisassembler;
-use crate::heap::{FunctionId, ValueId};
-use crate::native_functions::NativeFunctions;
-use crate::value::{Class, Closure, Instance, Upvalue};
+use crate::heap::{ArenaValue, FunctionId, ObjectId};
+use crate::native_functions::{self, NativeFunctions};
+use crate::value::{
+    Class, Closure, Generator, GeneratorState, Instance, MethodKind, Object, ResumedBy, Upvalue,
+};
 use crate::{
     chunk::{CodeOffset, OpCode},
     compiler::Compiler,
-    config,
-    heap::{Heap, StringId},
+    config::{self, ExecutionLimits, VmConfig},
+    heap::{now, GcStats, Heap, StringId},
+    heap_dump,
     scanner::Scanner,
     value::{NativeFunction, NativeFunctionImpl, Value},
+    verifier,
 };
 
 #[derive(Debug, PartialEq, Eq)]
@@ -23,6 +31,27 @@ pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    LimitExceeded(Limit),
+    /// The script called `exit(code)`
+    Exit(i32),
+}
+
+/// Why execution was stopped by `ExecutionLimits` or the cancellation handle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    InstructionBudget,
+    Timeout,
+    Cancelled,
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Limit::InstructionBudget => "Instruction budget exceeded.",
+            Limit::Timeout => "Timed out.",
+            Limit::Cancelled => "Cancelled.",
+        })
+    }
 }
 
 macro_rules! runtime_error {
@@ -36,8 +65,9 @@ macro_rules! runtime_error {
 }
 
 macro_rules! binary_op {
-    ($self:ident, $op:tt) => {
-        if !$self.binary_op(|a, b| a $op b) {
+    ($self:ident, $op:tt, $method:ident) => {
+        let method = $self.heap.builtin_constants().$method;
+        if !$self.binary_op(|a, b| a $op b, method) {
             return InterpretResult::RuntimeError;
         }
     }
@@ -46,25 +76,43 @@ macro_rules! binary_op {
 type BinaryOp<T> = fn(f64, f64) -> T;
 
 struct Global {
-    value: ValueId,
+    value: Value,
     mutable: bool,
 }
 
+/// What `OP_RETURN` does with a frame's result
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResultTo {
+    /// Pushes it, as for any call
+    Caller,
+    /// A `toString` call made by `OP_PRINT` or `OP_ECHO`, which prints the result
+    Print,
+    /// A `toString` call on the argument in `slot` of a call to a native that formats its
+    /// arguments. The result replaces the argument, and the native is called again.
+    NativeArg { slot: usize, arg_count: u8 },
+}
+
 pub struct CallFrame {
-    closure: ValueId,
+    closure: ObjectId,
     ip: usize,
     stack_base: usize,
+    result_to: ResultTo,
+    /// The generator running in this frame, which `OP_YIELD` suspends
+    generator: Option<ObjectId>,
+    /// An `init` run by calling the class, or by `super.init(...)` from one on the same instance.
+    /// `const` fields can only be set in these, not in an explicit `instance.init(...)`.
+    constructing: bool,
 }
 
 impl CallFrame {
     pub fn closure(&self) -> &Closure {
-        (*self.closure).as_closure()
+        self.closure.as_closure()
     }
 }
 
 struct CallStack {
     frames: Vec<CallFrame>,
-    current_closure: Option<ValueId>,
+    current_closure: Option<ObjectId>,
     current_function: Option<FunctionId>,
 }
 
@@ -93,11 +141,14 @@ impl CallStack {
         retval
     }
 
-    fn push(&mut self, closure: ValueId, stack_base: usize) {
+    fn push(&mut self, closure: ObjectId, stack_base: usize) {
         self.frames.push(CallFrame {
             closure,
             ip: 0,
             stack_base,
+            result_to: ResultTo::Caller,
+            generator: None,
+            constructing: false,
         });
         self.current_closure = Some(closure);
         self.current_function = Some(closure.as_closure().function);
@@ -117,7 +168,7 @@ impl CallStack {
         self.current_function.unwrap().chunk.code()[index]
     }
 
-    fn closure(&self) -> ValueId {
+    fn closure(&self) -> ObjectId {
         self.current_closure.unwrap()
     }
 
@@ -128,60 +179,323 @@ impl CallStack {
     fn len(&self) -> usize {
         self.frames.len()
     }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.current_closure = None;
+        self.current_function = None;
+    }
+}
+
+/// What natives get to see of the `VM`, besides the heap
+pub struct NativeContext<'vm> {
+    pub roots: Roots<'vm>,
+    /// See `VM::set_script_args`
+    pub script_args: &'vm [String],
+    /// Set to stop the script with `InterpretResult::Exit`
+    pub exit_code: Option<i32>,
+    /// Set to resume a generator; what it yields is the result of the call
+    pub resume: Option<ObjectId>,
+}
+
+/// The GC roots, for natives that need to look at the whole heap
+pub struct Roots<'vm> {
+    stack: &'vm [Value],
+    globals: &'vm HashMap<StringId, Global>,
+    callstack: &'vm CallStack,
+    open_upvalues: &'vm VecDeque<ObjectId>,
+}
+
+impl Roots<'_> {
+    /// Each root, with a name saying where it is
+    pub fn named(&self) -> Vec<(String, Value)> {
+        let mut globals = self
+            .globals
+            .iter()
+            .map(|(name, global)| (format!("global {}", **name), global.value))
+            .collect::<Vec<_>>();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let frames = self.callstack.iter().flat_map(|frame| {
+            let name = format!("frame {}", *frame.closure().function.name);
+            std::iter::once((name.clone(), frame.closure.into())).chain(
+                frame
+                    .generator
+                    .map(|generator| (format!("generator of {}", name), generator.into())),
+            )
+        });
+        let stack = self.stack.iter().enumerate().map(|(slot, value)| {
+            let frame = self
+                .callstack
+                .iter()
+                .rev()
+                .find(|frame| frame.stack_base <= slot);
+            let name = match frame {
+                Some(frame) => format!(
+                    "stack slot {} in {}",
+                    slot,
+                    *frame.closure().function.name
+                ),
+                None => format!("stack slot {}", slot),
+            };
+            (name, *value)
+        });
+        let open_upvalues = self.open_upvalues.iter().map(|upvalue| {
+            (
+                format!(
+                    "open upvalue for stack slot {}",
+                    upvalue.upvalue_location().as_open()
+                ),
+                (*upvalue).into(),
+            )
+        });
+
+        globals
+            .into_iter()
+            .chain(frames)
+            .chain(stack)
+            .chain(open_upvalues)
+            .collect()
+    }
 }
 
 pub struct VM {
     heap: Pin<Box<Heap>>,
     callstack: CallStack,
-    stack: Vec<ValueId>,
+    stack: Vec<Value>,
     globals: HashMap<StringId, Global>,
-    open_upvalues: VecDeque<ValueId>,
+    open_upvalues: VecDeque<ObjectId>,
+    config: VmConfig,
+    script_args: Vec<String>,
+    /// Set when a native asks to exit; the call fails so that the `VM` unwinds
+    exit_code: Option<i32>,
+
+    limits: ExecutionLimits,
+    cancelled: Arc<AtomicBool>,
+    /// For the current `interpret` call
+    instructions_left: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new(VmConfig::default())
+    }
 }
 
 impl VM {
     #[must_use]
-    pub fn new() -> Self {
-        Self {
-            heap: Heap::new(),
+    pub fn new(config: VmConfig) -> Self {
+        let capabilities = config.capabilities;
+        let mut vm = Self {
+            heap: Heap::new(config.gc.clone()),
             callstack: CallStack::new(),
             stack: Vec::with_capacity(crate::config::STACK_MAX),
             globals: HashMap::default(),
             open_upvalues: VecDeque::new(),
+            config,
+            script_args: vec![],
+            exit_code: None,
+
+            limits: ExecutionLimits::default(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            instructions_left: None,
+            deadline: None,
+        };
+        NativeFunctions::new(capabilities).define_functions(&mut vm);
+        vm
+    }
+
+    /// What the `args()` native returns
+    pub fn set_script_args(&mut self, args: Vec<String>) {
+        self.script_args = args;
+    }
+
+    /// Applies to each later call to `interpret`
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.limits = limits;
+    }
+
+    /// Setting the flag (from any thread) stops the running script with `Limit::Cancelled`. It's
+    /// cleared when that happens and when a script starts, so a stray cancellation while nothing
+    /// is running doesn't stop the next one.
+    pub fn cancellation_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    /// See `heap_dump::write`
+    pub fn heap_dump(&self, out: &mut impl Write) -> std::io::Result<()> {
+        heap_dump::write(&self.heap, &self.roots().named(), out)
+    }
+
+    fn roots(&self) -> Roots<'_> {
+        Roots {
+            stack: &self.stack,
+            globals: &self.globals,
+            callstack: &self.callstack,
+            open_upvalues: &self.open_upvalues,
         }
     }
 
     pub fn interpret(&mut self, source: &[u8]) -> InterpretResult {
-        let scanner = Scanner::new(source);
+        self.interpret_source(source, false)
+    }
+
+    /// Like `interpret`, for a line typed into the REPL: the values of top-level expression
+    /// statements are printed
+    pub fn interpret_line(&mut self, source: &[u8]) -> InterpretResult {
+        self.interpret_source(source, true)
+    }
+
+    /// Names and values of the globals, sorted by name
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals = self
+            .globals
+            .iter()
+            .map(|(name, global)| (name.to_string(), global.value))
+            .collect::<Vec<_>>();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        globals
+    }
+
+    /// Disassembly of the function, or the methods of the class, in the global `name`
+    pub fn disassemble(&self, name: &str) -> Result<String, String> {
+        let value = self
+            .globals
+            .iter()
+            .find(|(global, _)| ***global == name)
+            .map(|(_, global)| global.value)
+            .ok_or_else(|| format!("Undefined variable '{}'.", name))?;
+        match value.object() {
+            Some(Object::Closure(closure)) => Ok(format!("{:?}", closure.function.chunk)),
+            Some(Object::Class(class)) => {
+                let mut methods = MethodKind::ALL
+                    .iter()
+                    .flat_map(|kind| class.methods(*kind).values())
+                    .collect::<Vec<_>>();
+                methods.sort_by_key(|method| method.as_closure().function.name.to_string());
+                Ok(methods
+                    .into_iter()
+                    .map(|method| format!("{:?}", method.as_closure().function.chunk))
+                    .collect())
+            }
+            _ => Err(format!("'{}' is not a function or class.", name)),
+        }
+    }
 
-        let mut native_functions = NativeFunctions::new();
-        native_functions.create_names(&mut self.heap);
-        let mut compiler = Compiler::new(scanner, &mut self.heap);
-        native_functions.register_names(&mut compiler);
+    fn interpret_source(&mut self, source: &[u8], echo: bool) -> InterpretResult {
+        let scanner = Scanner::new(source);
 
-        let result = if let Some(function) = compiler.compile() {
-            native_functions.define_functions(self);
+        let mut compiler = Compiler::new(scanner, &mut self.heap, &self.config);
+        compiler.set_echo(echo);
+        let globals = &self.globals;
+        NativeFunctions::new(self.config.capabilities).deny_names(&mut compiler, |name| {
+            globals.keys().any(|global| **global == name)
+        });
 
+        let function = compiler.compile().filter(|function| {
+            // An error here is a bug in the compiler or the optimizer
+            verifier::verify(function)
+                .map_err(|e| error!("{}", e))
+                .is_ok()
+        });
+        let result = if let Some(function) = function {
             let function_id = self.heap.add_function(function);
-            let closure = Value::closure(function_id);
-            let value_id = self.heap.add_value(closure);
-            self.stack_push(value_id);
-            self.execute_call(value_id, 0);
+            let closure = Object::closure(function_id);
+            let closure_id = self.heap.add_object(closure);
+            self.stack_push(closure_id.into());
+            self.execute_call(closure_id, 0);
+            self.cancelled.store(false, Ordering::Relaxed);
+            self.instructions_left = self.limits.instruction_budget;
+            self.deadline = self
+                .limits
+                .timeout
+                .and_then(|timeout| Some(now()? + timeout));
             self.run()
         } else {
             InterpretResult::CompileError
         };
 
-        if result == InterpretResult::Ok {
-            assert_eq!(self.stack.len(), 0);
+        match result {
+            InterpretResult::Ok => assert_eq!(self.stack.len(), 0),
+            InterpretResult::RuntimeError
+            | InterpretResult::LimitExceeded(_)
+            | InterpretResult::Exit(_) => self.reset(),
+            InterpretResult::CompileError => {}
         }
         result
     }
 
+    /// Unwinds everything left over from a script that didn't run to completion. Globals are kept.
+    fn reset(&mut self) {
+        // Closures that outlive this may still point at the stack
+        self.close_upvalues(0);
+        for frame in self.callstack.iter() {
+            if let Some(mut generator) = frame.generator {
+                generator.as_generator_mut().state = GeneratorState::Done;
+            }
+        }
+        self.stack.clear();
+        self.callstack.clear();
+    }
+
+    /// How many instructions to execute before the limits need to be checked again
+    fn instructions_until_limit_check(&self) -> u64 {
+        self.instructions_left
+            .map_or(config::LIMIT_CHECK_INTERVAL, |left| {
+                left.min(config::LIMIT_CHECK_INTERVAL)
+            })
+    }
+
+    /// `executed` is the number of instructions since the last check
+    fn check_limits(&mut self, executed: u64) -> Result<(), Limit> {
+        if self.cancelled.swap(false, Ordering::Relaxed) {
+            return Err(Limit::Cancelled);
+        }
+        if let Some(left) = &mut self.instructions_left {
+            *left -= executed;
+            if *left == 0 {
+                return Err(Limit::InstructionBudget);
+            }
+        }
+        if let (Some(deadline), Some(now)) = (self.deadline, now()) {
+            if now >= deadline {
+                return Err(Limit::Timeout);
+            }
+        }
+        Ok(())
+    }
+
     fn run(&mut self) -> InterpretResult {
-        let trace_execution = config::TRACE_EXECUTION.load();
-        let stress_gc = config::STRESS_GC.load();
-        let std_mode = config::STD_MODE.load();
+        let trace_execution = self.config.trace_execution;
+        let stress_gc = self.config.gc.stress;
+        let std_mode = self.config.std_mode;
+        let mut limit_check_interval = self.instructions_until_limit_check();
+        let mut until_limit_check = limit_check_interval;
         loop {
+            // Checked before executing the instruction, so an instruction budget of `n` allows
+            // exactly `n` instructions
+            if until_limit_check == 0 {
+                if let Err(limit) = self.check_limits(limit_check_interval) {
+                    // Errors are reported at the instruction before `ip`; point it past the one
+                    // that was about to run instead. The call stack is reset afterwards anyway.
+                    self.callstack.current_mut().ip += 1;
+                    runtime_error!(self, "{}", limit);
+                    return InterpretResult::LimitExceeded(limit);
+                }
+                limit_check_interval = self.instructions_until_limit_check();
+                until_limit_check = limit_check_interval;
+            }
+            until_limit_check -= 1;
+
             if trace_execution {
                 let function = &self.callstack.function();
                 let mut disassembler = InstructionDisassembler::new(&function.chunk);
@@ -190,27 +504,45 @@ impl VM {
                     "          [ {} ]",
                     self.stack
                         .iter()
-                        .map(|v| format!("{}", self.heap.values[v]))
+                        .map(|v| format!("{}", v))
                         .collect::<Vec<_>>()
                         .join(" ][ ")
                 );
                 debug!("{:?}", disassembler);
             }
-            self.collect_garbage(stress_gc);
+            if !self.collect_garbage(stress_gc) {
+                runtime_error!(self, "Out of memory.");
+                return InterpretResult::RuntimeError;
+            }
             match OpCode::try_from(self.read_byte()).expect("Internal error: unrecognized opcode") {
                 OpCode::Print => {
-                    info!(
-                        "{}",
-                        *self.stack.pop().expect("stack underflow in OP_PRINT")
-                    );
+                    if let Some(called) = self.print_with_to_string() {
+                        if !called {
+                            return InterpretResult::RuntimeError;
+                        }
+                        continue;
+                    }
+                    // Not inside `info!`, whose arguments aren't evaluated when logging is off
+                    let value = self.stack.pop().expect("stack underflow in OP_PRINT");
+                    info!("{}", value.display(std_mode));
+                }
+                OpCode::Echo => {
+                    if let Some(called) = self.print_with_to_string() {
+                        if !called {
+                            return InterpretResult::RuntimeError;
+                        }
+                        continue;
+                    }
+                    let value = self.stack.pop().expect("stack underflow in OP_ECHO");
+                    if value != Value::Nil {
+                        info!("{}", value.display(std_mode));
+                    }
                 }
                 OpCode::Pop => {
                     self.stack.pop().expect("stack underflow in OP_POP");
                 }
                 OpCode::Dup => {
-                    self.stack_push_value(
-                        self.heap.values[self.peek(0).expect("stack underflow in OP_DUP")].clone(),
-                    );
+                    self.stack_push(*self.peek(0).expect("stack underflow in OP_DUP"));
                 }
                 op @ (OpCode::GetLocal | OpCode::GetLocalLong) => self.get_local(op),
                 op @ (OpCode::SetLocal | OpCode::SetLocalLong) => self.set_local(op),
@@ -257,8 +589,8 @@ impl VM {
                     let value = self.read_constant(true);
                     self.stack_push(value);
                 }
-                OpCode::Closure => {
-                    let value = self.read_constant(false);
+                op @ (OpCode::Closure | OpCode::ClosureLong) => {
+                    let value = self.read_constant(op == OpCode::ClosureLong);
                     let function = value.as_function();
                     let mut closure = Closure::new(*function);
 
@@ -277,16 +609,16 @@ impl VM {
                         } else {
                             closure
                                 .upvalues
-                                .push((*self.callstack.closure()).as_closure().upvalues[index]);
+                                .push(self.callstack.closure().as_closure().upvalues[index]);
                         }
                     }
 
-                    let closure_id = self.heap.add_value(Value::from(closure));
-                    self.stack_push(closure_id);
+                    let closure_id = self.heap.add_object(closure.into());
+                    self.stack_push(closure_id.into());
                 }
-                OpCode::Nil => self.stack_push(self.heap.builtin_constants().nil),
-                OpCode::True => self.stack_push(self.heap.builtin_constants().true_),
-                OpCode::False => self.stack_push(self.heap.builtin_constants().false_),
+                OpCode::Nil => self.stack_push(Value::Nil),
+                OpCode::True => self.stack_push(Value::Bool(true)),
+                OpCode::False => self.stack_push(Value::Bool(false)),
 
                 OpCode::Negate => {
                     if let Some(value) = self.negate() {
@@ -298,7 +630,9 @@ impl VM {
                 }
 
                 OpCode::Equal => {
-                    self.equal();
+                    if !self.equal() {
+                        return InterpretResult::RuntimeError;
+                    }
                 }
 
                 OpCode::Add => {
@@ -307,43 +641,55 @@ impl VM {
                     }
                 }
 
-                OpCode::Subtract => binary_op!(self, -),
-                OpCode::Multiply => binary_op!(self, *),
-                OpCode::Divide => binary_op!(self, /),
+                OpCode::Subtract => {
+                    binary_op!(self, -, subtract_method);
+                }
+                OpCode::Multiply => {
+                    binary_op!(self, *, multiply_method);
+                }
+                OpCode::Divide => {
+                    binary_op!(self, /, divide_method);
+                }
 
-                OpCode::Greater => binary_op!(self, >),
-                OpCode::Less => binary_op!(self, <),
+                OpCode::Greater => {
+                    binary_op!(self, >, greater_method);
+                }
+                OpCode::Less => {
+                    binary_op!(self, <, less_method);
+                }
 
                 OpCode::GetUpvalue => {
                     let upvalue_index = usize::from(self.read_byte());
-                    let closure_value = &*self.callstack.closure();
-                    let closure = closure_value.as_closure();
-                    let upvalue_location = closure.upvalues[upvalue_index].upvalue_location();
-                    match *upvalue_location {
+                    let closure = self.callstack.closure();
+                    let upvalue = closure.as_closure().upvalues[upvalue_index];
+                    match *upvalue.upvalue_location() {
                         Upvalue::Open(absolute_local_index) => {
                             self.stack_push(self.stack[absolute_local_index]);
                         }
-                        Upvalue::Closed(value_id) => self.stack_push(value_id),
+                        Upvalue::Closed(value) => self.stack_push(value),
+                        Upvalue::Suspended { generator, slot } => {
+                            self.stack_push(generator.as_generator().stack[slot]);
+                        }
                     }
                 }
                 OpCode::SetUpvalue => {
                     let upvalue_index = usize::from(self.read_byte());
-                    let upvalue_location = (*self.callstack.closure()).as_closure().upvalues
-                        [upvalue_index]
-                        .upvalue_location()
-                        // TODO get rid of this `.clone()`
-                        .clone();
-                    let new_value = self
+                    let mut upvalue = self.callstack.closure().as_closure().upvalues[upvalue_index];
+                    let new_value = *self
                         .stack
                         .last()
-                        .map(|x| self.heap.values[x].clone())
                         .expect("Stack underflow in OP_SET_UPVALUE");
-                    match upvalue_location {
+                    match upvalue.upvalue_location_mut() {
                         Upvalue::Open(absolute_local_index) => {
-                            *self.stack[absolute_local_index] = new_value;
+                            self.stack[*absolute_local_index] = new_value;
+                        }
+                        Upvalue::Closed(value) => {
+                            *value = new_value;
+                            self.heap.write_barrier(&upvalue, &new_value);
                         }
-                        Upvalue::Closed(mut value_id) => {
-                            *value_id = new_value;
+                        Upvalue::Suspended { generator, slot } => {
+                            generator.as_generator_mut().stack[*slot] = new_value;
+                            self.heap.write_barrier(generator, &new_value);
                         }
                     }
                 }
@@ -353,19 +699,25 @@ impl VM {
                     self.stack.pop();
                 }
 
-                OpCode::Class => {
-                    let class_name = self.read_string("OP_CLASS");
-                    let class = Class::new(class_name);
-                    self.stack_push_value(class.into());
+                op @ (OpCode::Class | OpCode::ClassLong | OpCode::Trait | OpCode::TraitLong) => {
+                    let class_name = self.read_string(op);
+                    let mut class = Class::new(class_name);
+                    class.is_trait = matches!(op, OpCode::Trait | OpCode::TraitLong);
+                    let class_id = self.heap.add_object(class.into());
+                    self.stack_push(class_id.into());
                 }
-                OpCode::GetProperty => {
-                    let field = self.read_string("GET_PROPERTY");
+                op @ (OpCode::GetProperty | OpCode::GetPropertyLong) => {
+                    let field = self.read_string(op);
 
-                    let instance = match &self.heap.values
-                        [self.peek(0).expect("Stack underflow in GET_PROPERTY")]
-                    {
-                        Value::Instance(instance) => instance.clone(),
-                        x => {
+                    let receiver = *self.peek(0).expect("Stack underflow in GET_PROPERTY");
+                    let (fields, class, kind) = match receiver.object() {
+                        Some(Object::Instance(instance)) => {
+                            (&instance.fields, instance.class, MethodKind::Instance)
+                        }
+                        Some(Object::Class(class)) if !std_mode => {
+                            (&class.fields, receiver.as_object(), MethodKind::Static)
+                        }
+                        _ => {
                             if std_mode {
                                 runtime_error!(self, "Only instances have properties.");
                             } else {
@@ -373,33 +725,41 @@ impl VM {
                                     self,
                                     "Tried to get property '{}' of non-instance `{}`.",
                                     *field,
-                                    x
+                                    receiver
                                 );
                             }
                             return InterpretResult::RuntimeError;
                         }
                     };
-                    if let Some(value) = instance.fields.get(&self.heap.strings[&field]) {
+                    if let Some(value) = fields.get(&*field) {
                         self.stack.pop(); // instance
                         self.stack_push(*value);
-                    } else if self.bind_method(instance.class, field) {
+                    } else if let Some(getter) = self.getter(class, kind, field) {
+                        // The getter's frame replaces the instance with its result
+                        if !self.execute_call(getter, 0) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else if self.bind_method(class, field, kind) {
                         // nothing to do here, bind_method has side effects
                     } else if !std_mode {
                         self.stack.pop(); // instance
-                        self.stack_push(self.heap.builtin_constants().nil);
+                        self.stack_push(Value::Nil);
                     } else {
                         runtime_error!(self, "Undefined property '{}'.", *field);
                         return InterpretResult::RuntimeError;
                     }
                 }
-                OpCode::SetProperty => {
-                    let field_string_id = self.read_string("SET_PROPERTY");
+                op @ (OpCode::SetProperty | OpCode::SetPropertyLong) => {
+                    let field_string_id = self.read_string(op);
                     let field = &self.heap.strings[&field_string_id];
 
-                    match &self.heap.values[self.peek(1).expect("Stack underflow in SET_PROPERTY")]
-                    {
-                        Value::Instance(instance) => instance,
-                        x => {
+                    let receiver = *self.peek(1).expect("Stack underflow in SET_PROPERTY");
+                    let setter = match receiver.object() {
+                        Some(Object::Instance(instance)) => {
+                            self.setter(instance.class, field_string_id)
+                        }
+                        Some(Object::Class(_)) if !std_mode => None,
+                        _ => {
                             if std_mode {
                                 runtime_error!(self, "Only instances have fields.");
                             } else {
@@ -407,72 +767,190 @@ impl VM {
                                     self,
                                     "Tried to set property '{}' of non-instance `{}`.",
                                     field,
-                                    x
+                                    receiver
                                 );
                             }
                             return InterpretResult::RuntimeError;
                         }
                     };
+                    if let Some(setter) = setter {
+                        // Setters return their argument, which is left in place of the instance
+                        if !self.execute_call(setter, 1) {
+                            return InterpretResult::RuntimeError;
+                        }
+                        continue;
+                    }
                     let value = self.stack.pop().expect("Stack underflow in SET_PROPERTY");
-                    let mut instance = self.stack.pop().expect("Stack underflow in SET_PROPERTY");
-                    instance
-                        .as_instance_mut()
-                        .fields
-                        .insert(field.to_string(), value);
+                    let mut instance = self
+                        .stack
+                        .pop()
+                        .expect("Stack underflow in SET_PROPERTY")
+                        .as_object();
+                    if let Err(message) = self.check_writable(instance, field_string_id) {
+                        runtime_error!(self, "{}", message);
+                        return InterpretResult::RuntimeError;
+                    }
+                    let fields = match &mut *instance {
+                        Object::Instance(instance) => &mut instance.fields,
+                        Object::Class(class) => &mut class.fields,
+                        _ => unreachable!(),
+                    };
+                    if fields.insert(field.to_string(), value).is_none() {
+                        self.heap
+                            .object_grew(Instance::field_size(&field_string_id));
+                    }
+                    self.heap.write_barrier(&instance, &value);
                     self.stack_push(value);
                 }
 
-                OpCode::Method => {
-                    let method_name = self.read_string("OP_METHOD");
-                    self.define_method(method_name);
+                op @ (OpCode::Method | OpCode::MethodLong) => {
+                    let method_name = self.read_string(op);
+                    self.define_method(method_name, MethodKind::Instance);
+                }
+                op @ (OpCode::StaticMethod | OpCode::StaticMethodLong) => {
+                    let method_name = self.read_string(op);
+                    self.define_method(method_name, MethodKind::Static);
+                }
+                op @ (OpCode::Getter | OpCode::GetterLong) => {
+                    let method_name = self.read_string(op);
+                    self.define_method(method_name, MethodKind::Getter);
+                }
+                op @ (OpCode::Setter | OpCode::SetterLong) => {
+                    let method_name = self.read_string(op);
+                    self.define_method(method_name, MethodKind::Setter);
                 }
 
-                OpCode::Invoke => {
-                    let method_name = self.read_string("OP_INVOKE");
+                op @ (OpCode::ConstField | OpCode::ConstFieldLong) => {
+                    let field = self.read_string(op);
+                    let mut class = self
+                        .peek(0)
+                        .expect("Stack underflow in OP_CONST_FIELD")
+                        .as_object();
+                    self.heap.write_barrier(&class, &field.into());
+                    if class.as_class_mut().const_fields.insert(field) {
+                        self.heap.object_grew(Class::CONST_FIELD_SIZE);
+                    }
+                }
+
+                op @ (OpCode::Invoke | OpCode::InvokeLong) => {
+                    let method_name = self.read_string(op);
                     let arg_count = self.read_byte();
                     if !self.invoke(method_name, arg_count) {
-                        return InterpretResult::RuntimeError;
+                        return self.call_failed();
                     }
                 }
 
                 OpCode::Inherit => {
-                    let superclass_id = self.peek(1).expect("Stack underflow in OP_INHERIT");
-                    let superclass = match &self.heap.values[superclass_id] {
-                        Value::Class(superclass) => superclass,
-                        _ => {
-                            runtime_error!(self, "Superclass must be a class.");
-                            return InterpretResult::RuntimeError;
-                        }
+                    let superclass = *self.peek(1).expect("Stack underflow in OP_INHERIT");
+                    let Some(Object::Class(superclass)) = superclass
+                        .object()
+                        .filter(|class| !class.as_class().is_trait)
+                    else {
+                        runtime_error!(self, "Superclass must be a class.");
+                        return InterpretResult::RuntimeError;
                     };
-                    let methods = superclass.methods.clone();
-                    let mut subclass = self.stack.pop().expect("Stack underflow in OP_INHERIT");
-                    subclass.as_class_mut().methods.extend(methods);
+                    let mut subclass = self
+                        .stack
+                        .pop()
+                        .expect("Stack underflow in OP_INHERIT")
+                        .as_object();
+                    // Left on the stack as the `super` local
+                    let superclass_id = self.peek(0).unwrap().as_object();
+                    self.heap.write_barrier(&subclass, &superclass_id.into());
+                    subclass.as_class_mut().superclass = Some(superclass_id);
+                    for kind in MethodKind::ALL {
+                        let methods = superclass.methods(kind).clone();
+                        for method in methods.values() {
+                            self.heap.write_barrier(&subclass, &(*method).into());
+                        }
+                        self.heap.object_grew(methods.len() * Class::METHOD_SIZE);
+                        subclass.as_class_mut().methods_mut(kind).extend(methods);
+                    }
+                    self.copy_const_fields(subclass, superclass_id);
                 }
 
-                OpCode::GetSuper => {
-                    let method_name = self.read_string("OP_GET_SUPER");
-                    let superclass = self.stack.pop().expect("Stack underflow in OP_GET_SUPER");
-                    if !self.bind_method(superclass, method_name) {
+                OpCode::MixIn => {
+                    if !self.mix_in() {
                         return InterpretResult::RuntimeError;
                     }
                 }
 
-                OpCode::SuperInvoke => {
-                    let method_name = self.read_string("OP_SUPER_INVOKE");
+                OpCode::GetIter => {
+                    if !self.get_iter() {
+                        return self.call_failed();
+                    }
+                }
+                op @ (OpCode::IterHasNext | OpCode::IterHasNextLong) => {
+                    let slot = self.read_slot(op);
+                    if !self.iter_has_next(slot) {
+                        return self.call_failed();
+                    }
+                }
+                op @ (OpCode::IterNext | OpCode::IterNextLong) => {
+                    let slot = self.read_slot(op);
+                    if !self.iter_next(slot) {
+                        return self.call_failed();
+                    }
+                }
+                OpCode::Yield => self.yield_(),
+
+                op @ (OpCode::GetSuper | OpCode::GetSuperLong) => {
+                    let method_name = self.read_string(op);
+                    let superclass = self
+                        .stack
+                        .pop()
+                        .expect("Stack underflow in OP_GET_SUPER")
+                        .as_object();
+                    let kind = self.super_kind(0);
+                    if let Some(getter) = self.getter(superclass, kind, method_name) {
+                        if !self.execute_call(getter, 0) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else if !self.bind_method(superclass, method_name, kind) {
+                        runtime_error!(self, "Undefined property '{}'.", *method_name);
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+
+                op @ (OpCode::SuperInvoke | OpCode::SuperInvokeLong) => {
+                    let method_name = self.read_string(op);
                     let arg_count = self.read_byte();
                     let superclass = self
                         .stack
                         .pop()
-                        .expect("Stack underflow in OP_SUPER_INVOKE");
-                    if !self.invoke_from_class(superclass, method_name, arg_count) {
+                        .expect("Stack underflow in OP_SUPER_INVOKE")
+                        .as_object();
+                    let kind = self.super_kind(arg_count.into());
+                    if !self.invoke_from_class(superclass, method_name, arg_count, kind) {
                         return InterpretResult::RuntimeError;
                     }
                 }
+
+                OpCode::AddLocals => {
+                    if let Some(value) = self.add_locals() {
+                        return value;
+                    }
+                }
+                OpCode::LessLocalConstant => {
+                    if !self.less_local_constant() {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::JumpIfFalsePop => {
+                    self.jump_if_false_pop();
+                }
+                OpCode::ReturnConstant => {
+                    let value = self.read_constant(false);
+                    self.stack_push(value);
+                    if let Some(value) = self.return_() {
+                        return value;
+                    }
+                }
             };
         }
     }
 
-    fn peek(&self, n: usize) -> Option<&ValueId> {
+    fn peek(&self, n: usize) -> Option<&Value> {
         if n >= self.stack.len() {
             None
         } else {
@@ -480,7 +958,7 @@ impl VM {
         }
     }
 
-    fn peek_mut(&mut self, n: usize) -> Option<&mut ValueId> {
+    fn peek_mut(&mut self, n: usize) -> Option<&mut Value> {
         if n >= self.stack.len() {
             None
         } else {
@@ -492,22 +970,21 @@ impl VM {
     fn add(&mut self) -> Option<InterpretResult> {
         let slice_start = self.stack.len() - 2;
 
-        let ok = match &self.stack[slice_start..] {
-            [left, right] => match (&self.heap.values[left], &self.heap.values[right]) {
+        let ok = match self.stack[slice_start..] {
+            [left, right] => match (left, right) {
                 (Value::Number(a), Value::Number(b)) => {
-                    let value = (*a + *b).into();
                     self.stack.pop();
                     self.stack.pop();
-                    self.stack_push_value(value);
+                    self.stack_push((a + b).into());
                     true
                 }
                 (Value::String(a), Value::String(b)) => {
                     // This could be optimized by allowing mutations via the heap
-                    let new_string = format!("{}{}", self.heap.strings[a], self.heap.strings[b]);
+                    let new_string = format!("{}{}", *a, *b);
                     let new_string_id = self.heap.add_string(new_string);
                     self.stack.pop();
                     self.stack.pop();
-                    self.stack_push_value(new_string_id.into());
+                    self.stack_push(new_string_id.into());
                     true
                 }
                 _ => false,
@@ -516,7 +993,11 @@ impl VM {
         };
 
         if !ok {
-            if config::STD_MODE.load() {
+            let method = self.heap.builtin_constants().add_method;
+            if let Some(called) = self.operator_method(method, 1) {
+                return (!called).then_some(InterpretResult::RuntimeError);
+            }
+            if self.config.std_mode {
                 runtime_error!(self, "Operands must be two numbers or two strings.");
             } else {
                 runtime_error!(
@@ -524,7 +1005,7 @@ impl VM {
                     "Operands must be two numbers or two strings. Got: [{}]",
                     self.stack[slice_start..]
                         .iter()
-                        .map(|v| format!("{}", self.heap.values[v]))
+                        .map(|v| format!("{}", v))
                         .collect::<Vec<_>>()
                         .join(", ")
                 );
@@ -534,24 +1015,23 @@ impl VM {
         None
     }
 
-    fn equal(&mut self) {
-        let left_id = self
+    fn equal(&mut self) -> bool {
+        let method = self.heap.builtin_constants().equal_method;
+        if let Some(called) = self.operator_method(method, 1) {
+            return called;
+        }
+        let left = self
             .stack
             .pop()
             .expect("stack underflow in OP_EQUAL (first)");
-        let right_id = self
+        let right = self
             .stack
             .pop()
             .expect("stack underflow in OP_EQUAL (second)");
-        let left = &self.heap.values[&left_id];
-        let right = &self.heap.values[&right_id];
 
-        // There's one case where equality-by-reference doesn't imply *actual* equality: NaN
-        let value = match (left, right) {
-            (Value::Number(left), Value::Number(right)) if left.is_nan() && right.is_nan() => false,
-            (left, right) => left_id == right_id || left == right,
-        };
-        self.stack_push(self.heap.builtin_constants().bool(value));
+        // Objects are equal only to themselves; numbers follow IEEE 754, so NaN != NaN
+        self.stack_push((left == right).into());
+        true
     }
 
     fn not_(&mut self) {
@@ -560,13 +1040,15 @@ impl VM {
             .pop()
             .expect("stack underflow in OP_NOT")
             .is_falsey();
-        self.stack_push(self.heap.builtin_constants().bool(value));
+        self.stack_push(value.into());
     }
 
     fn negate(&mut self) -> Option<InterpretResult> {
-        let value_id = *self.peek(0).expect("stack underflow in OP_NEGATE");
-        let value = &mut self.heap.values[&value_id];
-        match value {
+        let method = self.heap.builtin_constants().negate_method;
+        if let Some(called) = self.operator_method(method, 0) {
+            return (!called).then_some(InterpretResult::RuntimeError);
+        }
+        match self.peek_mut(0).expect("stack underflow in OP_NEGATE") {
             Value::Number(n) => *n = -*n,
             _ => {
                 runtime_error!(self, "Operand must be a number.");
@@ -588,18 +1070,64 @@ impl VM {
         }
     }
 
+    fn jump_if_false_pop(&mut self) {
+        let offset = self.read_16bit_number();
+        if self
+            .stack
+            .last()
+            .expect("stack underflow in OP_JUMP_IF_FALSE_POP")
+            .is_falsey()
+        {
+            self.callstack.current_mut().ip += offset;
+        } else {
+            self.stack.pop();
+        }
+    }
+
+    fn add_locals(&mut self) -> Option<InterpretResult> {
+        let left_slot = usize::from(self.read_byte());
+        let right_slot = usize::from(self.read_byte());
+        let left = *self.stack_get(left_slot);
+        let right = *self.stack_get(right_slot);
+        if let (Value::Number(a), Value::Number(b)) = (left, right) {
+            self.stack_push((a + b).into());
+            None
+        } else {
+            // Strings and type errors take the slow path
+            self.stack_push(left);
+            self.stack_push(right);
+            self.add()
+        }
+    }
+
+    fn less_local_constant(&mut self) -> bool {
+        let slot = usize::from(self.read_byte());
+        let left = *self.stack_get(slot);
+        let right = self.read_constant(false);
+        if let (Value::Number(a), Value::Number(b)) = (left, right) {
+            self.stack_push((a < b).into());
+            true
+        } else {
+            self.stack_push(left);
+            self.stack_push(right);
+            let method = self.heap.builtin_constants().less_method;
+            self.binary_op(|a, b| a < b, method)
+        }
+    }
+
     fn define_global(&mut self, op: OpCode) {
-        let constant = self.read_constant(op == OpCode::DefineGlobalLong);
-        match &self.heap.values[&constant] {
+        let constant = self.read_constant(op.is_long());
+        match constant {
             Value::String(name) => {
-                let name = *name;
+                let value = *self
+                    .stack
+                    .last()
+                    .unwrap_or_else(|| panic!("stack underflow in {:?}", op));
+                self.heap.global_write_barrier(&name, &value);
                 self.globals.insert(
                     name,
                     Global {
-                        value: *self
-                            .stack
-                            .last()
-                            .unwrap_or_else(|| panic!("stack underflow in {:?}", op)),
+                        value,
                         mutable: op != OpCode::DefineGlobalConst
                             && op != OpCode::DefineGlobalConstLong,
                     },
@@ -613,16 +1141,325 @@ impl VM {
         }
     }
 
-    fn define_method(&mut self, method_name: StringId) {
-        let method = *self.peek(0).expect("Stack underflow in OP_METHOD");
-        let class = self
-            .peek_mut(1)
+    fn define_method(&mut self, method_name: StringId, kind: MethodKind) {
+        let method = self
+            .peek(0)
             .expect("Stack underflow in OP_METHOD")
-            .as_class_mut();
-        class.methods.insert(method_name, method);
+            .as_object();
+        let mut class = self
+            .peek(1)
+            .expect("Stack underflow in OP_METHOD")
+            .as_object();
+        if class
+            .as_class_mut()
+            .methods_mut(kind)
+            .insert(method_name, method)
+            .is_none()
+        {
+            self.heap.object_grew(Class::METHOD_SIZE);
+        }
+        self.heap.write_barrier(&class, &method.into());
         self.stack.pop();
     }
 
+    /// `const` field declarations are inherited from superclasses and traits
+    fn copy_const_fields(&mut self, mut class: ObjectId, from: ObjectId) {
+        for name in from.as_class().const_fields.clone() {
+            self.heap.write_barrier(&class, &name.into());
+            if class.as_class_mut().const_fields.insert(name) {
+                self.heap.object_grew(Class::CONST_FIELD_SIZE);
+            }
+        }
+    }
+
+    /// Why a field can't be set on `object`, if it can't: a frozen instance, or a `const` field
+    /// outside of an `init` running on the instance
+    fn check_writable(&self, object: ObjectId, field: StringId) -> Result<(), String> {
+        let Object::Instance(instance) = &*object else {
+            return Ok(());
+        };
+        if instance.frozen {
+            return Err(format!(
+                "Can't set property '{}' of frozen instance `{}`.",
+                *field,
+                Value::from(object)
+            ));
+        }
+        if instance.class.as_class().const_fields.contains(&field) && !self.initializing(object) {
+            return Err(format!(
+                "Can't set const property '{}' outside of 'init'.",
+                *field
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the current frame is an `init` constructing `instance`
+    fn initializing(&self, instance: ObjectId) -> bool {
+        let frame = self.callstack.current();
+        frame.constructing && self.stack[frame.stack_base] == Value::from(instance)
+    }
+
+    /// Strings are iterated over by `OP_ITER_NEXT` itself. Instances with an `iter()` method are
+    /// replaced by what it returns, other instances are their own iterators.
+    fn get_iter(&mut self) -> bool {
+        let iterable = *self.peek(0).expect("Stack underflow in OP_GET_ITER");
+        if let Value::String(_) = iterable {
+            return true;
+        }
+        if let Some(Object::Generator(_)) = iterable.object() {
+            return true;
+        }
+        let Some(Object::Instance(instance)) = iterable.object() else {
+            runtime_error!(self, "Can't iterate over `{}`.", iterable);
+            return false;
+        };
+        let iter_method = self.heap.builtin_constants().iter_method;
+        if instance.fields.contains_key(&*iter_method)
+            || instance.class.as_class().methods.contains_key(&iter_method)
+        {
+            self.invoke(iter_method, 0)
+        } else {
+            true
+        }
+    }
+
+    /// The iterator is in local `slot`; for strings, the local after it is the byte offset of the
+    /// next character
+    fn iter_has_next(&mut self, slot: usize) -> bool {
+        let iterator = *self.stack_get(slot);
+        if let Value::String(string) = iterator {
+            let Value::Number(offset) = *self.stack_get(slot + 1) else {
+                unreachable!("for-in string position isn't a number");
+            };
+            self.stack_push(((offset as usize) < string.len()).into());
+            return true;
+        }
+        if let Some(Object::Generator(generator)) = iterator.object() {
+            if generator.next.is_some() || generator.state == GeneratorState::Done {
+                self.stack_push(generator.next.is_some().into());
+                return true;
+            }
+            return self.resume(iterator.as_object(), ResumedBy::HasNext);
+        }
+        self.stack_push(iterator);
+        self.invoke(self.heap.builtin_constants().has_next_method, 0)
+    }
+
+    fn iter_next(&mut self, slot: usize) -> bool {
+        let iterator = *self.stack_get(slot);
+        if let Value::String(string) = iterator {
+            let Value::Number(offset) = *self.stack_get(slot + 1) else {
+                unreachable!("for-in string position isn't a number");
+            };
+            let offset = offset as usize;
+            let Some(c) = string[offset..].chars().next() else {
+                runtime_error!(self, "Iterated past the end of the string.");
+                return false;
+            };
+            *self.stack_get_mut(slot + 1) = Value::Number((offset + c.len_utf8()) as f64);
+            let c = self.heap.add_string(c.to_string());
+            self.stack_push(c.into());
+            return true;
+        }
+        if let Some(Object::Generator(_)) = iterator.object() {
+            return self.resume(iterator.as_object(), ResumedBy::Resume);
+        }
+        self.stack_push(iterator);
+        self.invoke(self.heap.builtin_constants().next_method, 0)
+    }
+
+    /// Calling a `fun*` function: its callee slot and arguments are moved off the stack into a new
+    /// generator, which replaces them
+    fn create_generator(&mut self, closure: ObjectId, arg_count: u8) -> bool {
+        let arity = closure.as_closure().function.arity;
+        if usize::from(arg_count) != arity {
+            runtime_error!(self, "Expected {} arguments but got {}.", arity, arg_count);
+            return false;
+        }
+        let stack = self
+            .stack
+            .split_off(self.stack.len() - usize::from(arg_count) - 1);
+        let generator = self
+            .heap
+            .add_object(Object::Generator(Generator::new(closure, stack)));
+        self.stack_push(generator.into());
+        true
+    }
+
+    /// Puts the generator's frame back on top of the call stack. What it yields next ends up on
+    /// the stack as if it was the result of a call, as `resumed_by` says.
+    fn resume(&mut self, mut generator_id: ObjectId, resumed_by: ResumedBy) -> bool {
+        let generator = generator_id.as_generator_mut();
+        if let Some(next) = generator.next.take() {
+            // Already yielded by `OP_ITER_HAS_NEXT`
+            self.stack_push(next);
+            return true;
+        }
+        match generator.state {
+            GeneratorState::Running => {
+                runtime_error!(self, "Generator is already running.");
+                return false;
+            }
+            GeneratorState::Done => {
+                runtime_error!(self, "Can't resume a finished generator.");
+                return false;
+            }
+            GeneratorState::Suspended => {}
+        }
+
+        let stack_base = self.stack.len();
+        let closure = generator.closure;
+        if self.callstack.len() == crate::config::FRAMES_MAX
+            || stack_base + closure.as_closure().function.max_stack_depth > crate::config::STACK_MAX
+        {
+            runtime_error!(self, "Stack overflow.");
+            return false;
+        }
+
+        self.stack.append(&mut generator.stack);
+        // They're all above the ones that are open already, which are sorted highest first
+        for mut upvalue in generator.open_upvalues.drain(..).rev() {
+            let Upvalue::Suspended { slot, .. } = *upvalue.upvalue_location() else {
+                unreachable!("Open upvalue of a suspended generator isn't suspended");
+            };
+            *upvalue.upvalue_location_mut() = Upvalue::Open(stack_base + slot);
+            self.open_upvalues.push_front(upvalue);
+        }
+        generator.state = GeneratorState::Running;
+        generator.resumed_by = resumed_by;
+
+        self.callstack.push(closure, stack_base);
+        let frame = self.callstack.current_mut();
+        frame.ip = generator.ip;
+        frame.generator = Some(generator_id);
+        true
+    }
+
+    /// Saves the generator's frame and its part of the stack in the generator, and hands the
+    /// yielded value to whatever resumed it
+    fn yield_(&mut self) {
+        let value = self.stack.pop().expect("Stack underflow in OP_YIELD");
+        let frame = self
+            .callstack
+            .pop()
+            .expect("Call stack underflow in OP_YIELD");
+        let mut generator_id = frame.generator.expect("OP_YIELD outside of a generator");
+
+        let mut open_upvalues = vec![];
+        while let Some(mut upvalue) = self
+            .open_upvalues
+            .front()
+            .copied()
+            .filter(|upvalue| upvalue.upvalue_location().as_open() >= frame.stack_base)
+        {
+            self.open_upvalues.pop_front();
+            let slot = upvalue.upvalue_location().as_open() - frame.stack_base;
+            *upvalue.upvalue_location_mut() = Upvalue::Suspended {
+                generator: generator_id,
+                slot,
+            };
+            self.heap.write_barrier(&upvalue, &generator_id.into());
+            open_upvalues.push(upvalue);
+        }
+        let stack = self.stack.split_off(frame.stack_base);
+
+        let old_size = generator_id.size();
+        let generator = generator_id.as_generator_mut();
+        generator.stack = stack;
+        generator.open_upvalues = open_upvalues;
+        generator.ip = frame.ip;
+        generator.state = GeneratorState::Suspended;
+        let resumed_by = generator.resumed_by;
+        if resumed_by == ResumedBy::HasNext {
+            generator.next = Some(value);
+        }
+        self.heap
+            .object_grew(generator_id.size().saturating_sub(old_size));
+        let generator = generator_id.as_generator();
+        for value in generator.stack.iter().chain(&generator.next) {
+            self.heap.write_barrier(&generator_id, value);
+        }
+        for upvalue in &generator.open_upvalues {
+            self.heap.write_barrier(&generator_id, &(*upvalue).into());
+        }
+
+        match resumed_by {
+            ResumedBy::Resume => self.stack_push(value),
+            ResumedBy::HasNext => self.stack_push(true.into()),
+        }
+    }
+
+    /// Copies the methods of all kinds, and the `const` fields, from the traits into the class above
+    /// them, after the class body has run. Methods the class defines itself are kept; others,
+    /// including inherited ones, are replaced. A method defined by more than one trait is an error,
+    /// unless it's the same closure (a trait mixed into both) or the class defines it itself.
+    fn mix_in(&mut self) -> bool {
+        let trait_count = usize::from(self.read_byte());
+        let mut class = self
+            .stack
+            .pop()
+            .expect("Stack underflow in OP_MIX_IN")
+            .as_object();
+        let traits = self.stack.split_off(self.stack.len() - trait_count);
+        for kind in MethodKind::ALL {
+            // Inherited methods are the superclass's closures, copied down by `OP_INHERIT`
+            let defines_own = |name: &StringId| {
+                let class = class.as_class();
+                class.methods(kind).get(name).is_some_and(|method| {
+                    class.superclass.is_none_or(|superclass| {
+                        superclass.as_class().methods(kind).get(name) != Some(method)
+                    })
+                })
+            };
+            let mut methods: HashMap<StringId, (ObjectId, StringId)> = HashMap::default();
+            for trait_ in &traits {
+                let trait_ = match trait_.object() {
+                    Some(Object::Class(trait_)) if trait_.is_trait => trait_,
+                    _ => {
+                        runtime_error!(self, "Can only mix in traits, got `{}`.", trait_);
+                        return false;
+                    }
+                };
+                for (name, method) in trait_.methods(kind) {
+                    if defines_own(name) {
+                        continue;
+                    }
+                    match methods.get(name) {
+                        Some((other, other_trait)) if other != method => {
+                            runtime_error!(
+                                self,
+                                "Traits {} and {} both define '{}'.",
+                                **other_trait,
+                                *trait_.name,
+                                **name
+                            );
+                            return false;
+                        }
+                        _ => {
+                            methods.insert(*name, (*method, trait_.name));
+                        }
+                    }
+                }
+            }
+            for (name, (method, _)) in methods {
+                self.heap.write_barrier(&class, &method.into());
+                if class
+                    .as_class_mut()
+                    .methods_mut(kind)
+                    .insert(name, method)
+                    .is_none()
+                {
+                    self.heap.object_grew(Class::METHOD_SIZE);
+                }
+            }
+        }
+        for trait_ in traits {
+            self.copy_const_fields(class, trait_.as_object());
+        }
+        true
+    }
+
     fn return_(&mut self) -> Option<InterpretResult> {
         let result = self.stack.pop();
         let frame = self
@@ -635,7 +1472,33 @@ impl VM {
         }
         self.close_upvalues(frame.stack_base);
         self.stack.truncate(frame.stack_base);
-        self.stack_push(result.expect("Stack underflow in OP_RETURN"));
+        if let Some(mut generator) = frame.generator {
+            let generator = generator.as_generator_mut();
+            generator.state = GeneratorState::Done;
+            if generator.resumed_by == ResumedBy::HasNext {
+                self.stack_push(false.into());
+                return None;
+            }
+        }
+        let result = result.expect("Stack underflow in OP_RETURN");
+        match frame.result_to {
+            ResultTo::Caller => self.stack_push(result),
+            ResultTo::Print => info!("{}", result.display(self.config.std_mode)),
+            ResultTo::NativeArg { slot, arg_count } => {
+                // Always a string, so the native doesn't convert it again
+                self.stack[slot] = match result {
+                    Value::String(_) => result,
+                    _ => {
+                        let string = result.display(self.config.std_mode).to_string();
+                        self.heap.add_string(string).into()
+                    }
+                };
+                let callee = self.stack[self.stack.len() - 1 - usize::from(arg_count)];
+                if !self.call_value(callee, arg_count) {
+                    return Some(self.call_failed());
+                }
+            }
+        }
         None
     }
 
@@ -643,17 +1506,25 @@ impl VM {
         let arg_count = self.read_byte();
         let callee = self.stack[self.stack.len() - 1 - usize::from(arg_count)];
         if !self.call_value(callee, arg_count) {
-            return Some(InterpretResult::RuntimeError);
+            return Some(self.call_failed());
         }
         None
     }
 
+    /// Why `call_value` returned `false`: a runtime error, or a native asking to exit
+    fn call_failed(&mut self) -> InterpretResult {
+        match self.exit_code.take() {
+            Some(code) => InterpretResult::Exit(code),
+            None => InterpretResult::RuntimeError,
+        }
+    }
+
     fn set_global(&mut self, op: OpCode) -> Option<InterpretResult> {
         let constant_index = self.read_constant_index(op == OpCode::SetGlobalLong);
         let constant_value = self.read_constant_value(constant_index);
 
-        let name = match &self.heap.values[&constant_value] {
-            Value::String(name) => *name,
+        let name = match constant_value {
+            Value::String(name) => name,
             x => panic!(
                 "Internal error: non-string operand to OP_SET_GLOBAL: {:?}",
                 x
@@ -669,6 +1540,7 @@ impl VM {
                 .stack
                 .last()
                 .unwrap_or_else(|| panic!("stack underflow in {:?}", op));
+            self.heap.global_write_barrier(&name, &global.value);
         } else {
             runtime_error!(self, "Undefined variable '{}'.", *name);
             return Some(InterpretResult::RuntimeError);
@@ -680,11 +1552,22 @@ impl VM {
     fn get_global(&mut self, op: OpCode) -> Option<InterpretResult> {
         let constant_index = self.read_constant_index(op == OpCode::GetGlobalLong);
         let constant_value = self.read_constant_value(constant_index);
-        match &self.heap.values[&constant_value] {
-            Value::String(name) => match self.globals.get(name) {
+        match constant_value {
+            Value::String(name) => match self.globals.get(&name) {
                 Some(global) => self.stack_push(global.value),
                 None => {
-                    runtime_error!(self, "Undefined variable '{}'.", self.heap.strings[name]);
+                    if let Some(capability) =
+                        native_functions::missing_capability(&name, self.config.capabilities)
+                    {
+                        runtime_error!(
+                            self,
+                            "Native function '{}' needs the '{}' capability.",
+                            *name,
+                            capability
+                        );
+                    } else {
+                        runtime_error!(self, "Undefined variable '{}'.", *name);
+                    }
                     return Some(InterpretResult::RuntimeError);
                 }
             },
@@ -694,21 +1577,22 @@ impl VM {
     }
 
     fn set_local(&mut self, op: OpCode) {
-        let slot = if op == OpCode::GetLocalLong {
-            self.read_24bit_number()
-        } else {
-            usize::from(self.read_byte())
-        };
+        let slot = self.read_slot(op);
         *self.stack_get_mut(slot) = *self.peek(0).expect("stack underflow in OP_SET_LOCAL");
     }
 
     fn get_local(&mut self, op: OpCode) {
-        let slot = if op == OpCode::GetLocalLong {
+        let slot = self.read_slot(op);
+        self.stack_push(*self.stack_get(slot));
+    }
+
+    /// The local slot operand of `op`, a byte or 24 bits wide
+    fn read_slot(&mut self, op: OpCode) -> usize {
+        if op.is_long() {
             self.read_24bit_number()
         } else {
             usize::from(self.read_byte())
-        };
-        self.stack_push(*self.stack_get(slot));
+        }
     }
 
     fn read_byte(&mut self) -> u8 {
@@ -736,37 +1620,34 @@ impl VM {
         }
     }
 
-    fn read_constant_value(&self, index: usize) -> ValueId {
+    fn read_constant_value(&self, index: usize) -> Value {
         *self.callstack.function().chunk.get_constant(index)
     }
 
-    fn read_constant(&mut self, long: bool) -> ValueId {
+    fn read_constant(&mut self, long: bool) -> Value {
         let index = self.read_constant_index(long);
         self.read_constant_value(index)
     }
 
-    fn read_string(&mut self, opcode_name: &str) -> StringId {
-        let constant = self.read_constant(false);
-        match &self.heap.values[&constant] {
-            Value::String(string_id) => *string_id,
+    fn read_string(&mut self, op: OpCode) -> StringId {
+        match self.read_constant(op.is_long()) {
+            Value::String(string_id) => string_id,
             x => {
-                panic!("Non-string method name to {opcode_name}: `{x}`");
+                panic!("Non-string method name to {}: `{x}`", op.name());
             }
         }
     }
 
-    fn binary_op<T: Into<Value>>(&mut self, op: BinaryOp<T>) -> bool {
+    /// `method` is called instead if the left operand is an instance that has it
+    fn binary_op<T: Into<Value>>(&mut self, op: BinaryOp<T>, method: StringId) -> bool {
         let slice_start = self.stack.len() - 2;
 
-        let ok = match &self.stack[slice_start..] {
+        let ok = match self.stack[slice_start..] {
             [left, right] => {
-                if let (Value::Number(a), Value::Number(b)) =
-                    (&self.heap.values[left], &self.heap.values[right])
-                {
-                    let value = op(*a, *b).into();
+                if let (Value::Number(a), Value::Number(b)) = (left, right) {
                     self.stack.pop();
                     self.stack.pop();
-                    self.stack_push_value(value);
+                    self.stack_push(op(a, b).into());
                     true
                 } else {
                     false
@@ -775,44 +1656,114 @@ impl VM {
             _ => false,
         };
 
-        if !ok {
-            runtime_error!(self, "Operands must be numbers.");
+        if ok {
+            return true;
+        }
+        if let Some(called) = self.operator_method(method, 1) {
+            return called;
+        }
+        if let (false, Some(Object::Instance(instance))) =
+            (self.config.std_mode, self.stack[slice_start].object())
+        {
+            // `<=` and `>=` are compiled as `!(a > b)` and `!(a < b)`
+            let builtins = self.heap.builtin_constants();
+            let also_used_by = if method == builtins.greater_method {
+                " (which '<=' also uses)"
+            } else if method == builtins.less_method {
+                " (which '>=' also uses)"
+            } else {
+                ""
+            };
+            runtime_error!(
+                self,
+                "{} instance has no '{}' method{}.",
+                *instance.class.as_class().name,
+                *method,
+                also_used_by
+            );
+            return false;
         }
-        ok
+        runtime_error!(self, "Operands must be numbers.");
+        false
     }
 
-    #[inline]
-    fn stack_push(&mut self, value_id: ValueId) {
-        self.stack.push(value_id);
-        // This check has a pretty big performance overhead; disabled for now
-        // TODO find a better way: keep the check and minimize overhead
-        /*
-        if self.stack.len() > STACK_MAX {
-            runtime_error!(self, "Stack overflow");
+    /// Calls `method` on the operand `arg_count` slots down the stack, with the ones above it as
+    /// arguments, if it's an instance with that method. `None` if it isn't, so the operator does
+    /// what it does for other values; otherwise whether the call could be made.
+    fn operator_method(&mut self, method: StringId, arg_count: u8) -> Option<bool> {
+        if self.config.std_mode {
+            return None;
         }
-        */
+        let receiver = *self
+            .peek(arg_count.into())
+            .expect("Stack underflow in operator");
+        match receiver.object() {
+            Some(Object::Instance(instance))
+                if instance.class.as_class().methods.contains_key(&method) =>
+            {
+                Some(self.invoke_from_class(
+                    instance.class,
+                    method,
+                    arg_count,
+                    MethodKind::Instance,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// For `OP_PRINT` and `OP_ECHO`: calls `toString` on the value on top of the stack, if it's an
+    /// instance that has it. The frame prints the result when it returns. `None` if there's no
+    /// `toString` to call, otherwise whether the call could be made.
+    fn print_with_to_string(&mut self) -> Option<bool> {
+        let method = self.heap.builtin_constants().to_string_method;
+        let called = self.operator_method(method, 0)?;
+        if called {
+            self.callstack.current_mut().result_to = ResultTo::Print;
+        }
+        Some(called)
+    }
+
+    /// For natives that format their arguments, like `write`: calls `toString` on the first
+    /// argument that's an instance with that method, on a copy pushed on top of the stack. The
+    /// frame puts the result in the argument's place and calls the native again when it returns.
+    /// `None` if there's nothing left to convert, otherwise whether the call could be made.
+    fn native_arg_to_string(&mut self, arg_count: u8) -> Option<bool> {
+        if self.config.std_mode {
+            return None;
+        }
+        let method = self.heap.builtin_constants().to_string_method;
+        let args_start = self.stack.len() - usize::from(arg_count);
+        let (slot, class) = (args_start..self.stack.len()).find_map(|slot| {
+            match self.stack[slot].object() {
+                Some(Object::Instance(instance))
+                    if instance.class.as_class().methods.contains_key(&method) =>
+                {
+                    Some((slot, instance.class))
+                }
+                _ => None,
+            }
+        })?;
+        self.stack_push(self.stack[slot]);
+        let called = self.invoke_from_class(class, method, 0, MethodKind::Instance);
+        if called {
+            self.callstack.current_mut().result_to = ResultTo::NativeArg { slot, arg_count };
+        }
+        Some(called)
     }
 
+    /// Doesn't check for stack overflow: `execute_call` makes sure there's enough room for the
+    /// whole call up front
     #[inline]
-    fn stack_push_value(&mut self, value: Value) {
-        let value_id = match value {
-            Value::Bool(bool) => self.heap.builtin_constants().bool(bool),
-            Value::Nil => self.heap.builtin_constants().nil,
-            Value::Number(n) => self
-                .heap
-                .builtin_constants()
-                .number(n)
-                .unwrap_or_else(|| self.heap.add_value(value)),
-            value => self.heap.add_value(value),
-        };
-        self.stack.push(value_id);
+    fn stack_push(&mut self, value: Value) {
+        self.stack.push(value);
     }
 
-    fn stack_get(&self, slot: usize) -> &ValueId {
+    fn stack_get(&self, slot: usize) -> &Value {
         &self.stack[self.stack_base() + slot]
     }
 
-    fn stack_get_mut(&mut self, slot: usize) -> &mut ValueId {
+    fn stack_get_mut(&mut self, slot: usize) -> &mut Value {
         let offset = self.stack_base();
         &mut self.stack[offset + slot]
     }
@@ -821,11 +1772,23 @@ impl VM {
         self.callstack.current().stack_base
     }
 
-    fn call_value(&mut self, callee: ValueId, arg_count: u8) -> bool {
-        // error!("call_value {}", *callee);
-        match &self.heap.values[&callee] {
-            Value::Closure(_) => self.execute_call(callee, arg_count),
-            Value::NativeFunction(NativeFunction { fun, arity, name }) => {
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
+        // error!("call_value {}", callee);
+        let Value::Object(object_id) = callee else {
+            runtime_error!(self, "Can only call functions and classes.");
+            return false;
+        };
+        match &*object_id {
+            Object::Closure(closure) if closure.function.is_generator => {
+                self.create_generator(object_id, arg_count)
+            }
+            Object::Closure(_) => self.execute_call(object_id, arg_count),
+            Object::NativeFunction(NativeFunction {
+                fun,
+                arity,
+                name,
+                formats_args,
+            }) => {
                 if arg_count != *arity {
                     runtime_error!(
                         self,
@@ -836,12 +1799,36 @@ impl VM {
                     );
                     false
                 } else {
+                    if *formats_args {
+                        if let Some(called) = self.native_arg_to_string(arg_count) {
+                            return called;
+                        }
+                    }
                     let start_index = self.stack.len() - usize::from(arg_count);
-                    let args = self.stack[start_index..].iter().collect::<Vec<_>>();
-                    match fun(&mut self.heap, &args) {
+                    let mut context = NativeContext {
+                        roots: Roots {
+                            stack: &self.stack,
+                            globals: &self.globals,
+                            callstack: &self.callstack,
+                            open_upvalues: &self.open_upvalues,
+                        },
+                        script_args: &self.script_args,
+                        exit_code: None,
+                        resume: None,
+                    };
+                    let result = fun(&mut self.heap, &mut context, &self.stack[start_index..]);
+                    if let Some(code) = context.exit_code {
+                        self.exit_code = Some(code);
+                        return false;
+                    }
+                    let resume = context.resume;
+                    match result {
                         Ok(value) => {
                             self.stack
                                 .truncate(self.stack.len() - usize::from(arg_count) - 1);
+                            if let Some(generator) = resume {
+                                return self.resume(generator, ResumedBy::Resume);
+                            }
                             self.stack_push(value);
                             true
                         }
@@ -852,17 +1839,25 @@ impl VM {
                     }
                 }
             }
-            Value::Class(class) => {
+            Object::Class(class) if class.is_trait => {
+                runtime_error!(self, "Can't instantiate trait {}.", *class.name);
+                false
+            }
+            Object::Class(class) => {
                 let maybe_initializer = class
                     .methods
                     .get(&self.heap.builtin_constants().init_string)
-                    .cloned();
-                let instance_id: ValueId = self.heap.add_value(Instance::new(callee).into());
+                    .copied();
+                let instance_id = self.heap.add_object(Instance::new(object_id).into());
                 // Replace the class with the instance on the stack
                 let stack_index = self.stack.len() - usize::from(arg_count) - 1;
-                self.stack[stack_index] = instance_id;
+                self.stack[stack_index] = instance_id.into();
                 if let Some(initializer) = maybe_initializer {
-                    self.execute_call(initializer, arg_count)
+                    let called = self.execute_call(initializer, arg_count);
+                    if called {
+                        self.callstack.current_mut().constructing = true;
+                    }
+                    called
                 } else if arg_count != 0 {
                     runtime_error!(self, "Expected 0 arguments but got {arg_count}.");
                     false
@@ -870,7 +1865,7 @@ impl VM {
                     true
                 }
             }
-            Value::BoundMethod(bound_method) => {
+            Object::BoundMethod(bound_method) => {
                 let new_stack_base = self.stack.len() - usize::from(arg_count) - 1;
                 self.stack[new_stack_base] = bound_method.receiver;
                 self.execute_call(bound_method.method, arg_count)
@@ -882,66 +1877,106 @@ impl VM {
         }
     }
 
-    fn invoke_from_class(&mut self, class: ValueId, method_name: StringId, arg_count: u8) -> bool {
-        let Some(method) = class.as_class().methods.get(&method_name) else {
-            runtime_error!(self, "Undefined property '{}'.", self.heap.strings[&method_name]);
+    fn invoke_from_class(
+        &mut self,
+        class: ObjectId,
+        method_name: StringId,
+        arg_count: u8,
+        kind: MethodKind,
+    ) -> bool {
+        let Some(method) = class.as_class().methods(kind).get(&method_name) else {
+            if self.getter(class, kind, method_name).is_some() {
+                runtime_error!(
+                    self,
+                    "Can't call getter '{}' directly; wrap the property in parentheses to call its value.",
+                    *method_name
+                );
+            } else {
+                runtime_error!(self, "Undefined property '{}'.", *method_name);
+            }
             return false;
         };
         self.execute_call(*method, arg_count)
     }
 
     fn invoke(&mut self, method_name: StringId, arg_count: u8) -> bool {
-        let receiver = self
+        let receiver = *self
             .peek(arg_count.into())
             .expect("Stack underflow in OP_INVOKE");
-        //error!("invoke {}.{}", **receiver, *method_name);
-        if let Value::Instance(instance) = &self.heap.values[receiver] {
-            if let Some(value) = instance.fields.get(&self.heap.strings[&method_name]) {
-                let new_stack_base = self.stack.len() - usize::from(arg_count) - 1;
-                self.stack[new_stack_base] = *value;
-                self.call_value(*value, arg_count)
-            } else {
-                self.invoke_from_class(instance.class, method_name, arg_count)
+        //error!("invoke {}.{}", receiver, *method_name);
+        let (fields, class, kind) = match receiver.object() {
+            Some(Object::Instance(instance)) => {
+                (&instance.fields, instance.class, MethodKind::Instance)
+            }
+            Some(Object::Class(class)) if !self.config.std_mode => {
+                (&class.fields, receiver.as_object(), MethodKind::Static)
             }
+            _ => {
+                runtime_error!(self, "Only instances have methods.");
+                return false;
+            }
+        };
+        if let Some(value) = fields.get(&*method_name) {
+            let value = *value;
+            let new_stack_base = self.stack.len() - usize::from(arg_count) - 1;
+            self.stack[new_stack_base] = value;
+            self.call_value(value, arg_count)
         } else {
-            runtime_error!(self, "Only instances have methods.");
-            false
+            self.invoke_from_class(class, method_name, arg_count, kind)
         }
     }
 
-    fn bind_method(&mut self, class: ValueId, name: StringId) -> bool {
+    /// `class` methods are looked up on the superclass instead of methods when `this`, `depth`
+    /// slots down the stack, is a class: `super` is used in a `class` method
+    fn super_kind(&self, depth: usize) -> MethodKind {
+        match self.peek(depth).and_then(Value::object) {
+            Some(Object::Class(_)) => MethodKind::Static,
+            _ => MethodKind::Instance,
+        }
+    }
+
+    /// Only instances have getters and setters; `class` methods don't
+    fn getter(&self, class: ObjectId, kind: MethodKind, name: StringId) -> Option<ObjectId> {
+        match kind {
+            MethodKind::Instance => class.as_class().getters.get(&name).copied(),
+            _ => None,
+        }
+    }
+
+    fn setter(&self, class: ObjectId, name: StringId) -> Option<ObjectId> {
+        class.as_class().setters.get(&name).copied()
+    }
+
+    fn bind_method(&mut self, class: ObjectId, name: StringId, kind: MethodKind) -> bool {
         let class = class.as_class();
-        let Some(method) = class.methods.get(&name) else { return false; };
-        let bound_method = Value::bound_method(
+        let Some(method) = class.methods(kind).get(&name) else { return false; };
+        let bound_method = Object::bound_method(
             *self.peek(0).expect("Buffer underflow in OP_METHOD"),
             *method,
         );
         self.stack.pop();
-        self.stack_push_value(bound_method);
+        let bound_method_id = self.heap.add_object(bound_method);
+        self.stack_push(bound_method_id.into());
         true
     }
 
-    fn capture_upvalue(&mut self, local: usize) -> ValueId {
+    fn capture_upvalue(&mut self, local: usize) -> ObjectId {
         let local = self.callstack.current().stack_base + local;
-        let mut upvalue_index = 0;
-        let mut upvalue = None;
-
-        for (i, this) in self.open_upvalues.iter().enumerate() {
-            if this.upvalue_location().as_open() <= local {
-                break;
-            }
-            upvalue = Some(this);
-            upvalue_index = i;
-        }
+        // `open_upvalues` is sorted by stack slot, highest first
+        let upvalue_index = self
+            .open_upvalues
+            .iter()
+            .position(|this| this.upvalue_location().as_open() <= local)
+            .unwrap_or(self.open_upvalues.len());
 
-        if let Some(upvalue) = upvalue {
+        if let Some(upvalue) = self.open_upvalues.get(upvalue_index) {
             if upvalue.upvalue_location().as_open() == local {
                 return *upvalue;
             }
         }
 
-        let upvalue = Value::Upvalue(Upvalue::Open(local));
-        let upvalue_id = self.heap.add_value(upvalue);
+        let upvalue = Object::Upvalue(Upvalue::Open(local));
+        let upvalue_id = self.heap.add_object(upvalue);
         self.open_upvalues.insert(upvalue_index, upvalue_id);
 
         /*
@@ -968,7 +2003,7 @@ impl VM {
             .unwrap_or(false)
         {
             let mut upvalue = self.open_upvalues.pop_front().unwrap();
-            debug_assert!(matches!(*upvalue, Value::Upvalue(_)));
+            debug_assert!(matches!(*upvalue, Object::Upvalue(_)));
             /*
             error!(
                 "Closing stack index {} >= {}",
@@ -978,87 +2013,327 @@ impl VM {
             */
             let pointed_value = self.stack[upvalue.upvalue_location().as_open()];
             *upvalue.upvalue_location_mut() = Upvalue::Closed(pointed_value);
+            self.heap.write_barrier(&upvalue, &pointed_value);
         }
     }
 
-    fn execute_call(&mut self, closure: ValueId, arg_count: u8) -> bool {
-        let arity = closure.as_closure().function.arity;
+    fn execute_call(&mut self, closure: ObjectId, arg_count: u8) -> bool {
+        let function = closure.as_closure().function;
+        let arity = function.arity;
         let arg_count = usize::from(arg_count);
         if arg_count != arity {
             runtime_error!(self, "Expected {} arguments but got {}.", arity, arg_count);
             return false;
         }
 
-        if self.callstack.len() == crate::config::FRAMES_MAX {
+        let stack_base = self.stack.len() - arg_count - 1;
+        if self.callstack.len() == crate::config::FRAMES_MAX
+            || stack_base + function.max_stack_depth > crate::config::STACK_MAX
+        {
             runtime_error!(self, "Stack overflow.");
             return false;
         }
 
         debug_assert!(
-            matches!(*closure, Value::Closure(_)),
+            matches!(*closure, Object::Closure(_)),
             "`execute_call` must be called with a `Closure`, got: {}",
             *closure
         );
 
-        self.callstack
-            .push(closure, self.stack.len() - arg_count - 1);
+        let constructing = !self.callstack.is_empty() && {
+            let caller = self.callstack.current();
+            caller.constructing
+                && function.name == self.heap.builtin_constants().init_string
+                && self.stack[stack_base] == self.stack[caller.stack_base]
+        };
+        self.callstack.push(closure, stack_base);
+        self.callstack.current_mut().constructing = constructing;
         true
     }
 
-    pub fn define_native(&mut self, name: StringId, arity: u8, fun: NativeFunctionImpl) {
-        let value = Value::NativeFunction(NativeFunction {
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        formats_args: bool,
+        fun: NativeFunctionImpl,
+    ) {
+        let name = self.heap.add_string(name.to_string());
+        let native = Object::NativeFunction(NativeFunction {
             name: name.to_string(),
             arity,
+            formats_args,
             fun,
         });
-        let value_id = self.heap.add_value(value);
+        let native_id = self.heap.add_object(native);
 
+        self.heap.global_write_barrier(&name, &native_id.into());
         self.globals.insert(
             name,
             Global {
-                value: value_id,
+                value: native_id.into(),
                 mutable: false,
             },
         );
     }
 
-    fn collect_garbage(&mut self, stress_gc: bool) {
-        if !stress_gc && !self.heap.needs_gc() {
-            return;
+    /// Returns `false` if the heap is over the memory limit even after a full collection
+    fn collect_garbage(&mut self, stress_gc: bool) -> bool {
+        if !stress_gc && !self.heap.is_marking() && !self.heap.needs_gc() {
+            return true;
+        }
+
+        if self.heap.over_limit() {
+            let pause = self.heap.pause_start();
+            // Whatever was allocated during the current cycle survives it, so it takes one more
+            if self.heap.is_marking() {
+                self.finish_garbage_collection();
+            }
+            self.start_garbage_collection();
+            self.finish_garbage_collection();
+            self.heap.pause_end(pause);
+            return !self.heap.over_limit();
         }
-        let black_value = self.heap.black_value;
 
+        if !self.heap.is_marking() {
+            let pause = self.heap.pause_start();
+            self.start_garbage_collection();
+            self.heap.pause_end(pause);
+            return true;
+        }
+
+        let pause = self.heap.pause_start();
+        let step = if stress_gc {
+            config::GC_STRESS_STEP
+        } else {
+            config::GC_STEP
+        };
+        if self.heap.trace_step(step) {
+            self.finish_garbage_collection();
+        }
+        self.heap.pause_end(pause);
+        true
+    }
+
+    fn start_garbage_collection(&mut self) {
         self.heap.gc_start();
+        self.mark_roots();
+        for (name, global) in &self.globals {
+            self.heap.mark_value(&(*name).into());
+            self.heap.mark_value(&global.value);
+        }
+    }
 
-        // Mark roots
+    /// Roots that are written without a write barrier, so they're marked again when a cycle ends
+    fn mark_roots(&mut self) {
         for value in &self.stack {
             self.heap.mark_value(value);
         }
-        for global in self.globals.values() {
-            self.heap.mark_value(&global.value);
-        }
         for frame in self.callstack.iter() {
-            self.heap.mark_function(&frame.closure().function);
+            self.heap.mark_object(&frame.closure);
+            if let Some(generator) = &frame.generator {
+                self.heap.mark_object(generator);
+            }
         }
         for upvalue in &self.open_upvalues {
-            self.heap.mark_value(upvalue);
+            self.heap.mark_object(upvalue);
         }
+    }
 
-        // Trace references
+    fn finish_garbage_collection(&mut self) {
+        self.mark_roots();
         self.heap.trace();
+        self.heap.sweep();
+    }
+}
 
-        // Remove references to unmarked strings in `self.globals`
-        let globals_to_remove = self
-            .globals
-            .keys()
-            .filter(|string_id| !string_id.marked(black_value))
-            .cloned()
-            .collect::<Vec<_>>();
-        for id in globals_to_remove {
-            self.globals.remove(&id);
-        }
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-        // Finally, sweep
-        self.heap.sweep();
+    use super::*;
+    use crate::{
+        config::GcConfig,
+        native_functions::{Capabilities, Capability},
+    };
+
+    #[test]
+    fn memory_limit() {
+        let gc_config = GcConfig {
+            memory_limit: Some(256 * 1024),
+            ..GcConfig::default()
+        };
+
+        // Garbage is collected when the limit is reached, even before the first regular collection
+        let mut vm = VM::new(VmConfig {
+            gc: gc_config.clone(),
+            ..VmConfig::default()
+        });
+        assert_eq!(
+            vm.interpret(
+                b"class Node {} for (var i = 0; i < 20000; i = i + 1) { var node = Node(); node.x = i; }"
+            ),
+            InterpretResult::Ok
+        );
+        assert!(vm.gc_stats().collections > 0);
+
+        // Live objects aren't
+        let mut vm = VM::new(VmConfig {
+            gc: gc_config,
+            ..VmConfig::default()
+        });
+        assert_eq!(
+            vm.interpret(
+                b"class Node {} var head = nil; while (true) { var node = Node(); node.next = head; head = node; }"
+            ),
+            InterpretResult::RuntimeError
+        );
+        assert!(vm.gc_stats().bytes_allocated > 256 * 1024);
+    }
+
+    #[test]
+    fn execution_limits() {
+        let mut vm = VM::default();
+        vm.set_limits(ExecutionLimits {
+            instruction_budget: Some(10_000),
+            timeout: None,
+        });
+        assert_eq!(
+            vm.interpret(b"fun f() { while (true) {} } f();"),
+            InterpretResult::LimitExceeded(Limit::InstructionBudget)
+        );
+        // The budget is per `interpret`, and the VM is left usable
+        assert_eq!(vm.interpret(b"var y = 1; y = y + 1;"), InterpretResult::Ok);
+
+        vm.set_limits(ExecutionLimits {
+            instruction_budget: None,
+            timeout: Some(Duration::from_millis(50)),
+        });
+        assert_eq!(
+            vm.interpret(b"while (true) {}"),
+            InterpretResult::LimitExceeded(Limit::Timeout)
+        );
+
+        vm.set_limits(ExecutionLimits::default());
+        let cancel = vm.cancellation_handle();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(
+            vm.interpret(b"while (true) {}"),
+            InterpretResult::LimitExceeded(Limit::Cancelled)
+        );
+        canceller.join().unwrap();
+        assert_eq!(vm.interpret(b"print 1;"), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut vm = VM::new(VmConfig {
+            capabilities: Capabilities::pure(),
+            ..VmConfig::default()
+        });
+        assert_eq!(vm.interpret(b"sqrt(4);"), InterpretResult::Ok);
+        assert_eq!(vm.interpret(b"clock();"), InterpretResult::CompileError);
+        assert_eq!(
+            vm.interpret(b"clock(); var clock = 1;"),
+            InterpretResult::RuntimeError
+        );
+        // Declaring a global of the same name makes it the script's own, also for later scripts
+        assert_eq!(
+            vm.interpret(b"fun f() { return clock(); } fun clock() { return 1; } f();"),
+            InterpretResult::Ok
+        );
+        assert_eq!(vm.interpret(b"clock();"), InterpretResult::Ok);
+
+        let mut vm = VM::new(VmConfig {
+            capabilities: Capabilities::only(&[Capability::Pure, Capability::Time]),
+            ..VmConfig::default()
+        });
+        assert_eq!(vm.interpret(b"clock();"), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn exit() {
+        let mut vm = VM::default();
+        vm.set_script_args(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(
+            vm.interpret(b"fun f() { exit(args().next.next == nil and 3); } f(); exit(1);"),
+            InterpretResult::Exit(3)
+        );
+        assert_eq!(vm.interpret(b"print 1;"), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn repl_state() {
+        use crate::compiler::{Compiler, InputStatus};
+
+        assert_eq!(Compiler::input_status(b"fun f() {"), InputStatus::Incomplete);
+        assert_eq!(Compiler::input_status(b"print \"a"), InputStatus::Incomplete);
+        assert_eq!(Compiler::input_status(b"1 + 2"), InputStatus::Incomplete);
+        assert_eq!(Compiler::input_status(b"1 + 2;"), InputStatus::Complete);
+        assert_eq!(Compiler::input_status(b"1 + ; {"), InputStatus::Invalid);
+
+        // Globals and the strings they hold outlive each line, through collections
+        let gc_config = GcConfig {
+            initial_heap_size: 4096,
+            ..GcConfig::default()
+        };
+        let mut vm = VM::new(VmConfig {
+            gc: gc_config,
+            ..VmConfig::default()
+        });
+        assert_eq!(
+            vm.interpret_line(b"var greeting = \"hello\" + \" world\"; fun f() { return greeting; }"),
+            InterpretResult::Ok
+        );
+        assert_eq!(
+            vm.interpret_line(b"class Node {} for (var i = 0; i < 10000; i = i + 1) Node();"),
+            InterpretResult::Ok
+        );
+        assert_eq!(
+            vm.interpret_line(b"if (f() != \"hello world\") nil.x;"),
+            InterpretResult::Ok
+        );
+        assert!(vm.gc_stats().collections > 0);
+        assert!(vm.disassemble("f").unwrap().starts_with("== f =="));
+        assert!(vm.disassemble("greeting").is_err());
+        let globals = vm.globals();
+        let greeting = globals.iter().find(|(name, _)| name == "greeting").unwrap();
+        assert_eq!(greeting.1.to_string(), "hello world");
+    }
+
+    #[test]
+    fn std_mode_keywords() {
+        // Not keywords at all in `--std` mode, as in `clox`
+        let mut vm = VM::new(VmConfig {
+            std_mode: true,
+            ..VmConfig::default()
+        });
+        let source = "var break = 1; var yield = 2; break; yield;";
+        assert_eq!(vm.interpret(source.as_bytes()), InterpretResult::Ok);
+    }
+
+    #[test]
+    fn configs_in_parallel() {
+        // More constants than standard `clox` allows in one chunk
+        let source = (0..300)
+            .map(|i| format!("var v{} = {};", i, i))
+            .collect::<String>();
+        let threads = [false, true].map(|std_mode| {
+            let source = source.clone();
+            std::thread::spawn(move || {
+                let mut vm = VM::new(VmConfig {
+                    std_mode,
+                    optimize: true,
+                    ..VmConfig::default()
+                });
+                vm.interpret(source.as_bytes())
+            })
+        });
+        let [normal, std] = threads.map(|thread| thread.join().unwrap());
+        assert_eq!(normal, InterpretResult::Ok);
+        assert_eq!(std, InterpretResult::CompileError);
     }
 }
@@ -3,7 +3,7 @@ mod monaco_lox;
 
 use std::sync::Mutex;
 
-use clox_rs::{config, vm::VM};
+use clox_rs::{config::VmConfig, native_functions::Capabilities, vm::VM};
 use js_sys::Object;
 use log::{Level, LevelFilter, Metadata, Record};
 use monaco::{
@@ -87,7 +87,7 @@ impl log::Log for Logger {
 }
 static LOGGER: Logger = Logger::new();
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Default, PartialEq)]
 struct Flags {
     print_code: bool,
     trace_execution: bool,
@@ -98,14 +98,16 @@ struct Flags {
 
 impl Flags {
     #[must_use]
-    fn new() -> Self {
-        Self {
-            print_code: config::PRINT_CODE.load(),
-            trace_execution: config::TRACE_EXECUTION.load(),
-            std_mode: config::STD_MODE.load(),
-            stress_gc: config::STRESS_GC.load(),
-            log_gc: config::LOG_GC.load(),
-        }
+    fn vm_config(&self) -> VmConfig {
+        let mut config = VmConfig {
+            print_code: self.print_code,
+            trace_execution: self.trace_execution,
+            std_mode: self.std_mode,
+            ..VmConfig::default()
+        };
+        config.gc.stress = self.stress_gc;
+        config.gc.log = self.log_gc;
+        config
     }
 }
 
@@ -119,7 +121,7 @@ fn app() -> Html {
     // Store the code
     let code = use_state_eq(|| String::from(default_code));
     // Control behavior
-    let flags = use_state_eq(|| Flags::new());
+    let flags = use_state_eq(Flags::default);
     // Store the output
     let output = use_state_eq(|| Vec::new());
 
@@ -129,8 +131,12 @@ fn app() -> Html {
         let output = output.clone();
         let flags = flags.clone();
         use_effect_with_deps(
-            move |(code, _flags)| {
-                let mut vm = VM::new();
+            move |(code, flags)| {
+                // Scripts in the browser only get to compute things
+                let mut vm = VM::new(VmConfig {
+                    capabilities: Capabilities::pure(),
+                    ..flags.vm_config()
+                });
                 vm.interpret(code.as_bytes());
                 output.set(
                     LOGGER
@@ -205,21 +211,25 @@ fn app() -> Html {
         )
     };
 
-    // Handle checkboxes for global flags
+    // Handle checkboxes for the VM flags
     macro_rules! flag_handler {
         ($flags:ident, $n:ident) => {{
-            let $flags = $flags.clone();
-            move |checked: bool, _| {
-                config::$n.store(checked);
-                $flags.set(Flags::new());
-            }
+            use_callback(
+                move |checked: bool, $flags| {
+                    $flags.set(Flags {
+                        $n: checked,
+                        ..**$flags
+                    });
+                },
+                $flags.clone(),
+            )
         }};
     }
-    let on_show_bytecode_clicked = { use_callback(flag_handler!(flags, PRINT_CODE), ()) };
-    let on_trace_clicked = { use_callback(flag_handler!(flags, TRACE_EXECUTION), ()) };
-    let on_std_clicked = { use_callback(flag_handler!(flags, STD_MODE), ()) };
-    let on_stress_gc_clicked = { use_callback(flag_handler!(flags, STRESS_GC), ()) };
-    let on_log_gc_clicked = { use_callback(flag_handler!(flags, LOG_GC), ()) };
+    let on_show_bytecode_clicked = flag_handler!(flags, print_code);
+    let on_trace_clicked = flag_handler!(flags, trace_execution);
+    let on_std_clicked = flag_handler!(flags, std_mode);
+    let on_stress_gc_clicked = flag_handler!(flags, stress_gc);
+    let on_log_gc_clicked = flag_handler!(flags, log_gc);
 
     // What am I looking at?
     let open_help = use_callback(|_, _| micromodal::show("help-modal"), ());